@@ -0,0 +1,90 @@
+//! Reads transactions from stdin one line at a time, like `deposit,1,5,1.0`,
+//! and prints the client's balance after applying each one.
+
+use rust_coding_test::client::DEFAULT_ASSET;
+use rust_coding_test::exchange::Exchange;
+use rust_coding_test::format::{format_amount, DEFAULT_SCALE};
+use rust_coding_test::transaction::Transaction;
+use std::convert::TryFrom;
+use std::io::{self, BufRead, Write};
+
+/// Applies every line of the input to the exchange, writing the outcome of
+/// each to the output.
+fn run<R: BufRead, W: Write>(exchange: &mut Exchange, input: R, mut output: W) -> io::Result<()> {
+    for line in input.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let transaction = match Transaction::try_from(line.as_str()) {
+            Ok(transaction) => transaction,
+            Err(err) => {
+                writeln!(output, "invalid: {}", err)?;
+                continue;
+            }
+        };
+
+        let id = transaction.client();
+        if let Err(err) = exchange.process(transaction) {
+            writeln!(output, "failed: {:?}", err)?;
+            continue;
+        }
+
+        let (_, client) = match exchange.clients().find(|(&client, _)| client == id) {
+            Some(client) => client,
+            None => continue,
+        };
+        if let Some(balances) = client.balance(DEFAULT_ASSET) {
+            writeln!(
+                output,
+                "client {}: available {}, held {}, total {}, locked {}",
+                id,
+                format_amount(balances.funds_available, DEFAULT_SCALE),
+                format_amount(balances.funds_held, DEFAULT_SCALE),
+                format_amount(balances.funds_total(), DEFAULT_SCALE),
+                client.locked
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+fn main() {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+
+    if let Err(err) = run(&mut Exchange::new(), stdin.lock(), stdout.lock()) {
+        eprintln!("error: {}", err);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_prints_balance_after_each_line() {
+        let input = "deposit,1,1,2.5\n\
+                     \n\
+                     withdrawal,1,2,1.0\n\
+                     withdrawal,1,3,5.0\n\
+                     dispute,1,2\n\
+                     refund,1,1\n";
+        let mut output = Vec::new();
+
+        run(&mut Exchange::new(), input.as_bytes(), &mut output).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            "client 1: available 2.5000, held 0.0000, total 2.5000, locked false\n\
+             client 1: available 1.5000, held 0.0000, total 1.5000, locked false\n\
+             failed: InsufficientFunds\n\
+             client 1: available 1.5000, held 1.0000, total 2.5000, locked false\n\
+             invalid: unknown transaction type 'refund'\n"
+        );
+    }
+}
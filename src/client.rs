@@ -1,37 +1,169 @@
+use crate::collections::Map;
+use crate::money::{Amount, Money};
+use alloc::borrow::ToOwned;
+use alloc::string::String;
+#[cfg(feature = "bincode")]
+use serde::{Deserialize, Serialize};
+
 pub type ClientId = u16;
 
-/// Represents a client's account.
-#[derive(Debug, PartialEq)]
-pub struct Client {
+/// Assets are identified by their currency code (e.g. "BTC").
+pub type Asset = String;
+
+/// The asset used when a transaction doesn't specify one.
+pub const DEFAULT_ASSET: &str = "";
+
+/// Represents a client's funds for a single asset.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "bincode", derive(Serialize, Deserialize))]
+pub struct Balances<M = Money> {
     /// The total funds that are available for trading, staking, withdrawal,
     /// etc.
-    pub funds_available: f32,
+    pub funds_available: M,
 
     /// The total funds that are held for dispute.
-    pub funds_held: f32,
+    pub funds_held: M,
+}
+
+impl<M: Amount> Balances<M> {
+    /// Creates empty balances with no funds.
+    pub fn new() -> Balances<M> {
+        Balances {
+            funds_available: M::zero(),
+            funds_held: M::zero(),
+        }
+    }
+
+    pub fn funds_total(&self) -> M {
+        self.funds_available + self.funds_held
+    }
+
+    /// The proportion of the total funds that is held for disputes, or 0 if
+    /// there are no funds.
+    pub fn held_ratio(&self) -> f32 {
+        let total = self.funds_total().to_f32();
+        if total == 0.0 {
+            0.0
+        } else {
+            self.funds_held.to_f32() / total
+        }
+    }
+}
+
+impl<M: Amount> Default for Balances<M> {
+    fn default() -> Balances<M> {
+        Balances::new()
+    }
+}
+
+/// Represents a client's account.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "bincode", derive(Serialize, Deserialize))]
+pub struct Client<M = Money> {
+    /// The client's funds, kept separately for each asset.
+    pub balances: Map<Asset, Balances<M>>,
 
     /// Whether the account is locked. An account is locked if a charge back
     /// occurs.
     pub locked: bool,
 }
 
-impl Client {
+impl<M: Amount> Client<M> {
     /// Creates an empty client with no funds and not locked.
-    pub fn new() -> Client {
+    pub fn new() -> Client<M> {
         Client {
-            funds_available: 0.0,
-            funds_held: 0.0,
+            balances: Map::new(),
             locked: false,
         }
     }
 
-    pub fn funds_total(&self) -> f32 {
-        self.funds_available + self.funds_held
+    /// Gets the client's funds for the given asset, if the client has ever
+    /// held any of it.
+    pub fn balance(&self, asset: &str) -> Option<&Balances<M>> {
+        self.balances.get(asset)
+    }
+
+    /// Gets the client's funds for the given asset, creating empty balances if
+    /// needed.
+    pub fn balance_mut(&mut self, asset: &str) -> &mut Balances<M> {
+        self.balances.entry(asset.to_owned()).or_default()
+    }
+
+    /// The client's available, held, and total funds of the default asset, all
+    /// zero if there are none.
+    pub fn funds(&self) -> (M, M, M) {
+        match self.balance(DEFAULT_ASSET) {
+            Some(balances) => (
+                balances.funds_available,
+                balances.funds_held,
+                balances.funds_total(),
+            ),
+            None => (M::zero(), M::zero(), M::zero()),
+        }
+    }
+
+    /// The proportion of the client's funds of the default asset that is held
+    /// for disputes, or 0 if there are none. Use `Balances::held_ratio` for
+    /// other assets.
+    pub fn held_ratio(&self) -> f32 {
+        self.balance(DEFAULT_ASSET)
+            .map_or(0.0, Balances::held_ratio)
     }
 }
 
-impl Default for Client {
-    fn default() -> Client {
+impl<M: Amount> Default for Client<M> {
+    fn default() -> Client<M> {
         Client::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::money::money;
+
+    fn client(available: Money, held: Money) -> Client {
+        let mut client = Client::new();
+        *client.balance_mut(DEFAULT_ASSET) = Balances {
+            funds_available: available,
+            funds_held: held,
+        };
+        client
+    }
+
+    #[test]
+    fn funds_returns_available_held_and_total() {
+        let client = client(money("3.0"), money("1.5"));
+        let balances = client.balance(DEFAULT_ASSET).unwrap();
+
+        assert_eq!(
+            client.funds(),
+            (
+                balances.funds_available,
+                balances.funds_held,
+                balances.funds_total()
+            )
+        );
+        assert_eq!(client.funds(), (money("3.0"), money("1.5"), money("4.5")));
+        assert_eq!(
+            Client::<Money>::new().funds(),
+            (money("0.0"), money("0.0"), money("0.0"))
+        );
+    }
+
+    #[test]
+    fn held_ratio_is_zero_without_funds() {
+        assert_eq!(Client::<Money>::new().held_ratio(), 0.0);
+        assert_eq!(client(money("0.0"), money("0.0")).held_ratio(), 0.0);
+    }
+
+    #[test]
+    fn held_ratio_is_one_if_all_funds_are_held() {
+        assert_eq!(client(money("0.0"), money("2.5")).held_ratio(), 1.0);
+    }
+
+    #[test]
+    fn held_ratio_is_proportion_of_held_funds() {
+        assert_eq!(client(money("3.0"), money("1.0")).held_ratio(), 0.25);
+    }
+}
@@ -0,0 +1,8 @@
+//! The map the engine keeps clients and transactions in, and the matching set.
+//! They're a `HashMap` and `HashSet` with the `std` feature, otherwise a
+//! `BTreeMap` and `BTreeSet` since `alloc` has no hash maps.
+
+#[cfg(not(feature = "std"))]
+pub use alloc::collections::{btree_map::Entry, BTreeMap as Map, BTreeSet as Set};
+#[cfg(feature = "std")]
+pub use std::collections::{hash_map::Entry, HashMap as Map, HashSet as Set};
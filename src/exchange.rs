@@ -1,6 +1,22 @@
-use crate::client::{Client, ClientId};
+use crate::client::{Asset, Balances, Client, ClientId, DEFAULT_ASSET};
+use crate::collections::{Map, Set};
+#[cfg(feature = "std")]
+use crate::format::{format_amount, DEFAULT_SCALE};
+use crate::money::{approx_eq, Amount, Money};
 use crate::transaction::{Transaction, TransactionId as TxId};
-use std::collections::{hash_map::Entry, HashMap};
+use alloc::borrow::ToOwned;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+#[cfg(feature = "bincode")]
+use bincode_crate as bincode;
+#[cfg(feature = "bincode")]
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+#[cfg(feature = "std")]
+use std::io::Write;
+#[cfg(feature = "std")]
+use std::sync::mpsc::Sender;
 
 #[derive(Debug, Eq, PartialEq)]
 pub enum ExchangeError {
@@ -11,373 +27,4413 @@ pub enum ExchangeError {
     /// disputed again.
     TransactionAlreadyDisputed,
 
+    /// The original transaction was charged back, which is final, so it cannot
+    /// be disputed again.
+    TransactionChargedBack,
+
+    /// The original transaction was recorded before the client's current
+    /// session and the exchange only allows disputes within a session.
+    TransactionFromPriorSession,
+
     /// The original transaction has not been disputed so Resolve or Chargeback
     /// transactions are invalid.
     TransactionNotDisputed,
 
+    /// The original transaction's dispute has already been resolved, so it
+    /// cannot be resolved again.
+    TransactionAlreadyResolved,
+
     /// No transaction with that ID exists.
     TransactionNotFound,
 
     /// The client does not have enough funds to fulfill the transaction.
     InsufficientFunds,
+
+    /// The client's available funds are already negative, so the exchange
+    /// won't hold any more of them for a disputed withdrawal.
+    AvailableFundsNegative,
+
+    /// The amount is negative, infinite, or not a number.
+    InvalidAmount,
+
+    /// The amount is over the exchange's limit for a single transaction.
+    AmountExceedsLimit,
+
+    /// The withdrawal is for less than the exchange's minimum.
+    AmountBelowMinimum,
+
+    /// The amount is too large for the money type to represent exactly, so it
+    /// may have been rounded.
+    AmountLosesPrecision,
+
+    /// The transaction being disputed, resolved, or charged back belongs to
+    /// another client.
+    ClientMismatch,
+
+    /// The client ID is 0, which the exchange treats as reserved.
+    ReservedClientId,
+
+    /// The client doesn't have an account and the transaction isn't allowed to
+    /// create one.
+    AccountNotFound,
+
+    /// The client's account is locked.
+    AccountLocked,
+
+    /// The exchange's filter rejected the transaction.
+    Rejected,
+
+    /// The transaction would create an account but the exchange already has
+    /// as many clients as it allows.
+    TooManyClients,
 }
 
 use ExchangeError::*;
 
-/// Used by the exchange to keep track of transaction history
-enum TransactionState {
+/// Describes what processing a transaction did.
+#[derive(Debug, PartialEq)]
+pub enum ProcessOutcome<M = Money> {
+    /// Funds were deposited, leaving the given funds available.
+    Deposited { new_available: M },
+
+    /// Funds were withdrawn, leaving the given funds available.
+    Withdrawn { new_available: M },
+
+    /// Funds were transferred to another client, leaving the given funds
+    /// available to the sender.
+    Transferred { new_available: M },
+
+    /// A dispute held the given funds.
+    Held { amount: M },
+
+    /// A resolve released the given held funds.
+    Released { amount: M },
+
+    /// A chargeback removed the held funds and locked the account.
+    Locked,
+
+    /// The client started a new session.
+    SessionStarted,
+
+    /// A freeze locked the account.
+    Frozen,
+
+    /// An unfreeze unlocked the account.
+    Unfrozen,
+}
+
+/// The state of a transaction.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "bincode", derive(Serialize, Deserialize))]
+pub enum TransactionStatus {
     /// The transaction has been processed.
-    Completed(f32),
+    Completed,
+
+    /// The transaction has been disputed. The funds are held until the dispute
+    /// is resolved.
+    Disputed,
+
+    /// The transaction had a dispute that has been resolved by a Resolve
+    /// transaction.
+    Resolved,
+
+    /// The transaction had a dispute that has been resolved by a Chargeback
+    /// transaction.
+    ChargedBack,
+}
+
+use TransactionStatus::*;
+
+/// How many recorded transactions are in each state, see
+/// `Exchange::state_counts`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct StateCounts {
+    pub completed: usize,
+    pub disputed: usize,
+    pub resolved: usize,
+    pub charged_back: usize,
+}
+
+/// Used by the exchange to keep track of transaction history
+#[derive(Clone)]
+#[cfg_attr(feature = "bincode", derive(Serialize, Deserialize))]
+struct TransactionRecord<M> {
+    /// The client the transaction was for.
+    client: ClientId,
+
+    asset: Asset,
+
+    /// The amount moved by the transaction, negative for withdrawals.
+    amount: M,
+
+    status: TransactionStatus,
+
+    /// When the transaction happened, if the exchange was told, see
+    /// `Exchange::process_at`.
+    timestamp: Option<i64>,
+
+    /// When the transaction was last disputed, if the exchange knew the time.
+    disputed_at: Option<i64>,
+
+    /// Whether the client started a new session since the transaction.
+    prior_session: bool,
+
+    /// The fee charged on top of the amount, only ever for withdrawals.
+    fee: M,
+}
+
+/// An administrative change to a client's available funds, kept for auditing.
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "bincode", derive(Serialize, Deserialize))]
+pub struct Adjustment<M = Money> {
+    pub client: ClientId,
+
+    /// The amount added to the available funds, negative for debits.
+    pub delta: M,
+
+    /// Why the adjustment was made.
+    pub reason: String,
+}
+
+/// A transfer of funds from one client to another, kept so the balances can be
+/// replayed. See `Transaction::Transfer`.
+#[derive(Debug, PartialEq)]
+#[cfg_attr(feature = "bincode", derive(Serialize, Deserialize))]
+pub struct TransferRecord<M = Money> {
+    pub tx: TxId,
+    pub from: ClientId,
+    pub to: ClientId,
+    pub asset: Asset,
+    pub amount: M,
+}
+
+/// How much of an asset a client deposited and withdrew in total, whatever
+/// became of the transactions since, see `Exchange::flows`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "bincode", derive(Serialize, Deserialize))]
+pub struct Flows<M = Money> {
+    pub deposited: M,
+    pub withdrawn: M,
+}
+
+impl<M: Amount> Flows<M> {
+    /// Creates flows with nothing deposited or withdrawn.
+    pub fn new() -> Flows<M> {
+        Flows {
+            deposited: M::zero(),
+            withdrawn: M::zero(),
+        }
+    }
+
+    /// The deposits minus the withdrawals.
+    pub fn net(&self) -> M {
+        self.deposited - self.withdrawn
+    }
+}
+
+impl<M: Amount> Default for Flows<M> {
+    fn default() -> Flows<M> {
+        Flows::new()
+    }
+}
+
+/// How a client's account differs between two exchanges, see
+/// `Exchange::diff`.
+#[derive(Debug, PartialEq)]
+pub struct ClientDiff<M = Money> {
+    pub client: ClientId,
+
+    /// The change in funds of each asset that differs, as the other exchange's
+    /// balances minus this one's.
+    pub balances: Map<Asset, Balances<M>>,
+
+    /// The other exchange's locked flag, if it differs from this one's.
+    pub locked: Option<bool>,
+}
+
+/// A fee charged on each withdrawal, see `ExchangeBuilder::withdrawal_fee`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "bincode", derive(Serialize, Deserialize))]
+pub enum Fee<M = Money> {
+    /// The same fee whatever the amount.
+    Flat(M),
+
+    /// A fee that's this fraction of the amount, like 0.01 for 1%.
+    Rate(M),
+}
+
+impl<M: Amount> Fee<M> {
+    /// The fee for withdrawing the amount.
+    pub fn on(&self, amount: M) -> M {
+        match *self {
+            Fee::Flat(fee) => fee,
+            Fee::Rate(rate) => amount * rate,
+        }
+    }
+
+    /// The most that can be withdrawn from the available funds, so that the
+    /// amount plus its fee is all of them.
+    pub fn most_from(&self, available: M) -> M {
+        match *self {
+            Fee::Flat(fee) => available - fee,
+            Fee::Rate(rate) => available / (M::one() + rate),
+        }
+    }
+
+    /// Whether the fee can be charged: a flat fee must be a valid amount and a
+    /// rate between 0 and 1, so the fee never adds funds or overflows.
+    fn is_valid(&self) -> bool {
+        match *self {
+            Fee::Flat(fee) => validate_amount(fee, None).is_ok(),
+            Fee::Rate(rate) => rate.is_valid() && rate >= M::zero() && rate <= M::one(),
+        }
+    }
+}
+
+/// How recorded transactions are looked up. The client is only part of the key
+/// if transaction IDs are unique per client rather than globally.
+type TxKey = (Option<ClientId>, TxId);
+
+/// Sent whenever a client's balances change, see
+/// `Exchange::set_event_sender`.
+#[cfg(feature = "std")]
+#[derive(Debug, PartialEq)]
+pub struct ClientEvent<M = Money> {
+    pub client: ClientId,
+    pub asset: Asset,
+
+    /// The client's balances for the asset after the change.
+    pub balances: Balances<M>,
+}
+
+/// Vetoes transactions before they're applied, see `Exchange::set_filter`.
+type Filter<M> = Box<dyn Fn(&Transaction<M>) -> bool>;
+
+/// The exchange handles all transactions.
+///
+/// It keeps track of clients and transaction history. It handles deposits,
+/// withdrawals, and the dispute resolution process. All actions are done via
+/// transactions.
+///
+/// A recorded transaction's ID is never freed for reuse, whatever state the
+/// transaction ends up in. A deposit, withdrawal, or transfer reusing it fails
+/// with `TransactionAlreadyExists` even after a dispute was resolved or charged
+/// back. Transfers' IDs are taken the same way.
+///
+/// It is generic over the money type, which is `Money` unless chosen otherwise,
+/// e.g. `Exchange::<f64>::default()`.
+pub struct Exchange<M = Money> {
+    transactions: Map<TxKey, TransactionRecord<M>>,
+    // the order transactions were recorded in, since the map loses it
+    transaction_order: Vec<TxKey>,
+    // each client's transaction IDs in the order they were recorded
+    client_transactions: Map<ClientId, Vec<TxId>>,
+    adjustments: Vec<Adjustment<M>>,
+    transfers: Vec<TransferRecord<M>>,
+    // the keys of the transfers' IDs, so they aren't reused
+    transfer_keys: Set<TxKey>,
+    clients: Clients<M>,
+    // each client's total deposits and withdrawals of each asset
+    flows: Map<ClientId, Map<Asset, Flows<M>>>,
+    // how many disputes were ever filed, whatever became of them
+    disputes: u64,
+    // the withdrawal fees collected, whatever became of the withdrawals
+    fees: M,
+    config: Config<M>,
+    filter: Option<Filter<M>>,
+    // the latest time given to `expire_disputes`, used to date disputes
+    now: Option<i64>,
+    #[cfg(feature = "std")]
+    events: Option<Sender<ClientEvent<M>>>,
+    #[cfg(feature = "std")]
+    audit: Option<Box<dyn Write>>,
+    // audit lines held back until the atomic batch they're from succeeds
+    #[cfg(feature = "std")]
+    audit_batch: Option<Vec<String>>,
+}
+
+/// The policies an exchange follows, set up by the `ExchangeBuilder`.
+#[cfg_attr(feature = "bincode", derive(Serialize, Deserialize))]
+struct Config<M> {
+    /// Whether only deposits can create accounts.
+    strict_accounts: bool,
+
+    /// Whether a dispute may leave the client with negative available funds.
+    allow_negative_available: bool,
+
+    /// Whether deposits and withdrawals fail for locked accounts.
+    enforce_locks: bool,
+
+    /// Whether disputing a withdrawal fails while the client's available
+    /// funds are negative.
+    guard_negative_disputes: bool,
+
+    /// The largest amount a single deposit or withdrawal may have, if limited.
+    max_amount: Option<M>,
+
+    /// The smallest amount a withdrawal may take.
+    min_withdrawal: M,
+
+    /// The fee charged on each withdrawal, if any.
+    withdrawal_fee: Option<Fee<M>>,
+
+    /// Whether transaction IDs are only unique per client.
+    per_client_tx_ids: bool,
+
+    /// Whether a withdrawal over the available funds takes what's available.
+    partial_withdrawals: bool,
+
+    /// The largest difference between two amounts that are still considered
+    /// equal.
+    epsilon: M,
+
+    /// The most distinct clients the exchange keeps, if limited.
+    max_clients: Option<usize>,
+
+    /// How many seconds a dispute may stay open before it expires, if ever.
+    dispute_window: Option<i64>,
+
+    /// Whether clients are kept in ID order.
+    ordered_clients: bool,
+
+    /// Whether disputes may only target transactions from the client's
+    /// current session.
+    session_disputes: bool,
+
+    /// Whether deposits and withdrawals are recorded so they can be disputed.
+    record_transactions: bool,
+
+    /// Whether client ID 0 is reserved, so transactions for it are rejected.
+    forbid_client_zero: bool,
+
+    /// Whether resubmitting an identical deposit or withdrawal succeeds
+    /// without doing anything.
+    idempotent: bool,
+}
+
+/// Configures and creates an exchange.
+pub struct ExchangeBuilder<M = Money> {
+    config: Config<M>,
+}
+
+impl ExchangeBuilder {
+    /// Creates a builder with the default policies.
+    pub fn new() -> ExchangeBuilder {
+        ExchangeBuilder::default()
+    }
+}
+
+impl<M: Amount> ExchangeBuilder<M> {
+    /// Sets whether only deposits can create accounts. If set, any other
+    /// transaction for an unknown client fails with `AccountNotFound` instead
+    /// of creating an empty account, so every client's first transaction must
+    /// be a successful deposit.
+    pub fn strict_accounts(mut self, strict: bool) -> ExchangeBuilder<M> {
+        self.config.strict_accounts = strict;
+        self
+    }
+
+    /// Sets whether a dispute may leave the client with negative available
+    /// funds, e.g. when a deposit is disputed after it was already withdrawn.
+    /// If not set, such a dispute fails with `InsufficientFunds`.
+    pub fn allow_negative_available(mut self, allow: bool) -> ExchangeBuilder<M> {
+        self.config.allow_negative_available = allow;
+        self
+    }
+
+    /// Sets whether deposits, withdrawals, and transfers to a locked account
+    /// fail with `AccountLocked`, whether a chargeback or a freeze locked it.
+    /// Transfers from and adjustments to locked accounts always fail. Locked
+    /// accounts still take deposits, withdrawals, and incoming transfers by
+    /// default.
+    pub fn enforce_locks(mut self, enforce: bool) -> ExchangeBuilder<M> {
+        self.config.enforce_locks = enforce;
+        self
+    }
+
+    /// Sets whether disputing a withdrawal fails with `AvailableFundsNegative`
+    /// while the client's available funds are negative, so a client already
+    /// owing the exchange can't compound it with more disputes. Only exchanges
+    /// allowing negative available funds can get there. Such disputes are
+    /// allowed by default.
+    pub fn guard_negative_disputes(mut self, guard: bool) -> ExchangeBuilder<M> {
+        self.config.guard_negative_disputes = guard;
+        self
+    }
+
+    /// Sets the largest amount a single deposit or withdrawal may have. Larger
+    /// ones fail with `AmountExceedsLimit`. There is no limit by default.
+    pub fn max_amount(mut self, max: M) -> ExchangeBuilder<M> {
+        self.config.max_amount = Some(max);
+        self
+    }
+
+    /// Sets the smallest amount a single withdrawal may take. Withdrawals of
+    /// less, including partial withdrawals that would take less, fail with
+    /// `AmountBelowMinimum`. There is no minimum by default.
+    pub fn min_withdrawal(mut self, min: M) -> ExchangeBuilder<M> {
+        self.config.min_withdrawal = min;
+        self
+    }
+
+    /// Sets whether transaction IDs are only unique per client rather than
+    /// globally. If set, different clients can use the same ID, and a dispute,
+    /// resolve, or chargeback refers to the disputing client's own transaction.
+    pub fn per_client_tx_ids(mut self, per_client: bool) -> ExchangeBuilder<M> {
+        self.config.per_client_tx_ids = per_client;
+        self
+    }
+
+    /// Sets a fee charged on every withdrawal, taken from the available funds
+    /// on top of the amount, so a withdrawal fails with `InsufficientFunds`
+    /// unless both are available. With partial withdrawals, a withdrawal over
+    /// the available funds takes them all, fee included. A disputed withdrawal
+    /// only holds and returns the amount, the fee is kept. See
+    /// `Exchange::total_fees`. There's no fee by default.
+    ///
+    /// A flat fee must be a valid amount, i.e. not negative, infinite, or NaN,
+    /// and a rate must be between 0 and 1. Any other fee is ignored, leaving
+    /// withdrawals without one.
+    pub fn withdrawal_fee(mut self, fee: Fee<M>) -> ExchangeBuilder<M> {
+        self.config.withdrawal_fee = Some(fee).filter(Fee::is_valid);
+        self
+    }
+
+    /// Sets whether a withdrawal over the available funds withdraws only what
+    /// is available, recording that as the amount withdrawn. If not set, such
+    /// a withdrawal fails with `InsufficientFunds`.
+    pub fn partial_withdrawals(mut self, partial: bool) -> ExchangeBuilder<M> {
+        self.config.partial_withdrawals = partial;
+        self
+    }
+
+    /// Sets the largest difference between two amounts that are still
+    /// considered equal when reconciling. Defaults to the money type's
+    /// epsilon.
+    pub fn epsilon(mut self, epsilon: M) -> ExchangeBuilder<M> {
+        self.config.epsilon = epsilon;
+        self
+    }
+
+    /// Sets the most distinct clients the exchange keeps, to bound its memory.
+    /// Transactions that would create another account fail with
+    /// `TooManyClients`. There is no limit by default.
+    pub fn max_clients(mut self, max: usize) -> ExchangeBuilder<M> {
+        self.config.max_clients = Some(max);
+        self
+    }
+
+    /// Sets how many seconds a dispute may stay open. Disputes older than that
+    /// are resolved by `Exchange::expire_disputes`. Disputes never expire by
+    /// default.
+    pub fn dispute_window(mut self, seconds: i64) -> ExchangeBuilder<M> {
+        self.config.dispute_window = Some(seconds);
+        self
+    }
+
+    /// Sets whether clients are kept in a `BTreeMap`, so `Exchange::clients`
+    /// iterates over them in ID order without sorting, at the cost of slower
+    /// inserts and lookups. They're kept unordered by default.
+    pub fn ordered_clients(mut self, ordered: bool) -> ExchangeBuilder<M> {
+        self.config.ordered_clients = ordered;
+        self
+    }
+
+    /// Sets whether a dispute may only target a transaction from the client's
+    /// current session, which starts at its latest `SessionBoundary`. If set,
+    /// disputing an earlier transaction fails with
+    /// `TransactionFromPriorSession`. Disputes may target any transaction by
+    /// default.
+    pub fn session_disputes(mut self, within_session: bool) -> ExchangeBuilder<M> {
+        self.config.session_disputes = within_session;
+        self
+    }
+
+    /// Sets whether deposits and withdrawals are recorded. Without a record
+    /// there's nothing to dispute, so disputes, resolves, and chargebacks all
+    /// fail with `TransactionNotFound`, and reused transaction IDs aren't
+    /// detected. It saves the memory and time of keeping the history for
+    /// inputs known to have no disputes. There's also no history to replay,
+    /// so `recompute_balances` and `reconcile` aren't meaningful. Transactions
+    /// are recorded by default.
+    pub fn record_transactions(mut self, record: bool) -> ExchangeBuilder<M> {
+        self.config.record_transactions = record;
+        self
+    }
+
+    /// Sets whether client ID 0 is reserved, e.g. as a sentinel in another
+    /// system. If set, any transaction for client 0 fails with
+    /// `ReservedClientId`. Client 0 is an ordinary client by default.
+    pub fn forbid_client_zero(mut self, forbid: bool) -> ExchangeBuilder<M> {
+        self.config.forbid_client_zero = forbid;
+        self
+    }
+
+    /// Sets whether deposits and withdrawals are idempotent. If set, one that
+    /// reuses a recorded transaction's ID with the same client, asset, and
+    /// amount is taken to be a resubmission and succeeds without changing
+    /// anything. One that differs in any of them still fails with
+    /// `TransactionAlreadyExists`, as every reuse does by default.
+    pub fn idempotent(mut self, idempotent: bool) -> ExchangeBuilder<M> {
+        self.config.idempotent = idempotent;
+        self
+    }
+
+    /// Creates an empty exchange with the configured policies.
+    pub fn build(self) -> Exchange<M> {
+        Exchange {
+            transactions: Map::new(),
+            transaction_order: Vec::new(),
+            client_transactions: Map::new(),
+            adjustments: Vec::new(),
+            transfers: Vec::new(),
+            transfer_keys: Set::new(),
+            clients: Clients::new(self.config.ordered_clients),
+            flows: Map::new(),
+            disputes: 0,
+            fees: M::zero(),
+            config: self.config,
+            filter: None,
+            now: None,
+            #[cfg(feature = "std")]
+            events: None,
+            #[cfg(feature = "std")]
+            audit: None,
+            #[cfg(feature = "std")]
+            audit_batch: None,
+        }
+    }
+}
+
+impl<M: Amount> Default for ExchangeBuilder<M> {
+    fn default() -> ExchangeBuilder<M> {
+        ExchangeBuilder {
+            config: Config {
+                strict_accounts: false,
+                allow_negative_available: false,
+                enforce_locks: false,
+                guard_negative_disputes: false,
+                max_amount: None,
+                min_withdrawal: M::zero(),
+                withdrawal_fee: None,
+                per_client_tx_ids: false,
+                partial_withdrawals: false,
+                epsilon: M::epsilon(),
+                max_clients: None,
+                dispute_window: None,
+                ordered_clients: false,
+                session_disputes: false,
+                record_transactions: true,
+                forbid_client_zero: false,
+                idempotent: false,
+            },
+        }
+    }
+}
+
+impl Exchange {
+    /// Creates an empty exchange with the default policies.
+    pub fn new() -> Exchange {
+        ExchangeBuilder::new().build()
+    }
+
+    /// Creates an empty exchange with the default policies whose clients are
+    /// iterated over in ID order. See `ExchangeBuilder::ordered_clients`.
+    pub fn new_ordered() -> Exchange {
+        ExchangeBuilder::new().ordered_clients(true).build()
+    }
+
+    /// Creates an empty exchange with the default policies and room for the
+    /// given numbers of clients and transactions, so a workload of known size
+    /// doesn't have to grow its maps as it goes. Only the hash maps of the
+    /// `std` feature can be pre-sized.
+    #[cfg(feature = "std")]
+    pub fn with_capacity(clients: usize, transactions: usize) -> Exchange {
+        let mut exchange = Exchange::new();
+
+        exchange.transactions.reserve(transactions);
+        exchange.transaction_order.reserve(transactions);
+        exchange.client_transactions.reserve(clients);
+        exchange.clients.reserve(clients);
+        exchange.flows.reserve(clients);
+
+        exchange
+    }
+}
+
+impl<M: Amount> Exchange<M> {
+    /// Applies the transaction to the exchange, like `process`, at the given
+    /// time in seconds since the Unix epoch, e.g. the timestamp of its row.
+    /// The time is kept with a deposit or withdrawal and dates a dispute, so
+    /// `expire_disputes` can tell how long it's been open. It also counts as
+    /// time passing, see `expire_disputes`.
+    pub fn process_at(
+        &mut self,
+        transaction: Transaction<M>,
+        timestamp: i64,
+    ) -> Result<ProcessOutcome<M>, ExchangeError> {
+        use Transaction::*;
+
+        let key = self.key(transaction.client(), transaction.tx_id());
+        let (recorded, disputed) = match transaction {
+            Deposit(..) | Withdrawal(..) => (true, false),
+            Dispute(..) => (false, true),
+            _ => (false, false),
+        };
+        let outcome = self.process(transaction)?;
+        self.now = Some(self.now.map_or(timestamp, |now| now.max(timestamp)));
+        if let Some(record) = self.transactions.get_mut(&key) {
+            if recorded {
+                // a resubmission keeps the original's time
+                record.timestamp.get_or_insert(timestamp);
+            }
+            if disputed {
+                record.disputed_at = Some(timestamp);
+            }
+        }
+
+        Ok(outcome)
+    }
+
+    /// Applies the transaction to the exchange.
+    ///
+    /// This never panics, any transaction that can't be applied is reported as
+    /// an error and leaves the exchange unchanged.
+    pub fn process(
+        &mut self,
+        transaction: Transaction<M>,
+    ) -> Result<ProcessOutcome<M>, ExchangeError> {
+        use Transaction::*;
+
+        if self.config.forbid_client_zero && transaction.client() == 0 {
+            return Err(ReservedClientId);
+        }
+
+        if let Some(filter) = &self.filter {
+            if !filter(&transaction) {
+                return Err(Rejected);
+            }
+        }
+
+        #[cfg(feature = "std")]
+        let audit = self.audit.as_ref().map(|_| audit_fields(&transaction));
+        // kept to report the change once the transaction is consumed, which
+        // is only worth working out if there's anyone to report it to
+        #[cfg(feature = "std")]
+        let changed = match (&self.events, &transaction) {
+            (None, _) | (_, SessionBoundary(..)) | (_, Freeze(..)) | (_, Unfreeze(..)) => {
+                Vec::new()
+            }
+            (_, Deposit(client, _, asset, _)) | (_, Withdrawal(client, _, asset, _)) => {
+                vec![(*client, asset.clone())]
+            }
+            (_, Transfer(from, to, _, asset, _)) => {
+                vec![(*from, asset.clone()), (*to, asset.clone())]
+            }
+            (_, Dispute(client, tx)) | (_, Resolve(client, tx)) | (_, Chargeback(client, tx)) => {
+                self.transactions
+                    .get(&self.key(*client, *tx))
+                    .map(|record| (*client, record.asset.clone()))
+                    .into_iter()
+                    .collect()
+            }
+        };
+
+        let outcome = match transaction {
+            Deposit(client, tx, asset, amount) => self.deposit(tx, client, &asset, amount),
+            Withdrawal(client, tx, asset, amount) => self.withdraw(tx, client, &asset, amount),
+            Transfer(from, to, tx, asset, amount) => self.transfer(tx, from, to, asset, amount),
+            Dispute(client, tx) => self.dispute(tx, client),
+            Resolve(client, tx) => self.resolve(tx, client),
+            Chargeback(client, tx) => self.chargeback(tx, client),
+            SessionBoundary(client, _) => self.start_session(client),
+            Freeze(client, _) => self.set_locked(client, true),
+            Unfreeze(client, _) => self.set_locked(client, false),
+        }?;
+
+        #[cfg(feature = "std")]
+        for (client, asset) in changed {
+            self.notify(client, &asset);
+        }
+        #[cfg(feature = "std")]
+        if let Some(fields) = audit {
+            self.audit(format_args!("{}{}", fields, outcome_fields(&outcome)));
+        }
+
+        Ok(outcome)
+    }
+
+    /// Applies the transactions in order, all or nothing. If one fails, the
+    /// ones before it are rolled back, leaving the exchange as it was, and the
+    /// failing transaction's index in the batch is returned with its error.
+    ///
+    /// The state is copied before the batch so it can be restored, which costs
+    /// as much as the exchange is big. Events for rolled back transactions
+    /// have already been sent, but the batch's audit lines are only written
+    /// once all of it has been applied.
+    pub fn apply_atomic(
+        &mut self,
+        transactions: Vec<Transaction<M>>,
+    ) -> Result<(), (usize, ExchangeError)> {
+        let transactions_before = self.transactions.clone();
+        let order_before = self.transaction_order.len();
+        let client_transactions_before = self.client_transactions.clone();
+        let transfers_before = self.transfers.len();
+        let clients_before = self.clients.clone();
+        let flows_before = self.flows.clone();
+        let disputes_before = self.disputes;
+        let fees_before = self.fees;
+        #[cfg(feature = "std")]
+        {
+            self.audit_batch = Some(Vec::new());
+        }
+
+        for (i, transaction) in transactions.into_iter().enumerate() {
+            if let Err(err) = self.process(transaction) {
+                #[cfg(feature = "std")]
+                {
+                    self.audit_batch = None;
+                }
+                self.transactions = transactions_before;
+                self.transaction_order.truncate(order_before);
+                self.client_transactions = client_transactions_before;
+                for transfer in self.transfers.drain(transfers_before..) {
+                    let key = tx_key(self.config.per_client_tx_ids, transfer.from, transfer.tx);
+                    self.transfer_keys.remove(&key);
+                }
+                self.clients = clients_before;
+                self.flows = flows_before;
+                self.disputes = disputes_before;
+                self.fees = fees_before;
+                return Err((i, err));
+            }
+        }
+
+        #[cfg(feature = "std")]
+        if let (Some(lines), Some(writer)) = (self.audit_batch.take(), &mut self.audit) {
+            for line in lines {
+                let _ = writeln!(writer, "{}", line);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sends a `ClientEvent` on the channel every time a transaction or
+    /// adjustment changes a client's balances. The channel is unbounded so
+    /// processing never blocks on it, and events are dropped once the receiver
+    /// is gone.
+    #[cfg(feature = "std")]
+    pub fn set_event_sender(&mut self, events: Sender<ClientEvent<M>>) {
+        self.events = Some(events);
+    }
+
+    /// Sends the client's current balances for the asset to the event sender,
+    /// if there is one.
+    #[cfg(feature = "std")]
+    fn notify(&self, client: ClientId, asset: &str) {
+        let events = match &self.events {
+            Some(events) => events,
+            None => return,
+        };
+
+        if let Some(balances) = self.clients.get(&client).and_then(|c| c.balance(asset)) {
+            // a receiver that hung up just doesn't want any more events
+            let _ = events.send(ClientEvent {
+                client,
+                asset: asset.to_owned(),
+                balances: balances.clone(),
+            });
+        }
+    }
+
+    /// Appends a line to the writer for every change `process` applies, and
+    /// for every adjustment, unlock, and expired dispute, e.g. for compliance.
+    /// Each line is the kind of change followed by `key=value` fields, like
+    /// `deposit client=1 tx=5 amount=1.5000 available=1.5000`, with amounts
+    /// written to `DEFAULT_SCALE` decimal places. It replaces any writer set
+    /// before.
+    ///
+    /// Write errors are ignored, since the change has already been applied by
+    /// then, so use a writer that can't lose lines if every one matters.
+    #[cfg(feature = "std")]
+    pub fn set_audit_writer(&mut self, w: Box<dyn Write>) {
+        self.audit = Some(w);
+    }
+
+    /// Appends the record to the audit writer as a line, if there is one, or
+    /// holds it back while an atomic batch is being applied.
+    #[cfg(feature = "std")]
+    fn audit(&mut self, record: core::fmt::Arguments<'_>) {
+        let writer = match &mut self.audit {
+            Some(writer) => writer,
+            None => return,
+        };
+        match &mut self.audit_batch {
+            Some(lines) => lines.push(record.to_string()),
+            None => {
+                let _ = writeln!(writer, "{}", record);
+            }
+        }
+    }
+
+    /// Installs a filter that can veto transactions for custom business rules.
+    /// `process` skips any transaction it returns false for with `Rejected`.
+    /// It replaces any filter installed before.
+    pub fn set_filter(&mut self, filter: impl Fn(&Transaction<M>) -> bool + 'static) {
+        self.filter = Some(Box::new(filter));
+    }
+
+    pub fn clients(&self) -> impl Iterator<Item = (&ClientId, &Client<M>)> {
+        self.clients.iter()
+    }
+
+    /// Folds the clients into a single value, like `Iterator::fold`, e.g. to
+    /// compute an aggregate in one pass without collecting them.
+    pub fn fold_clients<B>(&self, init: B, mut f: impl FnMut(B, &ClientId, &Client<M>) -> B) -> B {
+        self.clients
+            .iter()
+            .fold(init, |acc, (id, client)| f(acc, id, client))
+    }
+
+    /// Iterates over the clients that have funds held for disputes in any
+    /// asset.
+    pub fn clients_with_holds(&self) -> impl Iterator<Item = (&ClientId, &Client<M>)> {
+        self.clients_where(|client| {
+            client
+                .balances
+                .values()
+                .any(|balances| balances.funds_held != M::zero())
+        })
+    }
+
+    /// Iterates over the clients the predicate returns true for.
+    pub fn clients_where<'a>(
+        &'a self,
+        pred: impl Fn(&Client<M>) -> bool + 'a,
+    ) -> impl Iterator<Item = (&'a ClientId, &'a Client<M>)> + 'a {
+        self.clients.iter().filter(move |(_, client)| pred(client))
+    }
+
+    /// Copies every client out of the exchange, sorted by ID, e.g. to compare
+    /// the whole state against an expected one in a test.
+    pub fn clients_sorted(&self) -> Vec<(ClientId, Client<M>)> {
+        let mut clients = self
+            .clients
+            .iter()
+            .map(|(&id, client)| (id, client.clone()))
+            .collect::<Vec<_>>();
+        clients.sort_unstable_by_key(|&(id, _)| id);
+
+        clients
+    }
+
+    /// Clears the client's locked flag, e.g. after a manual review. This is an
+    /// administrative action and isn't recorded as a transaction.
+    pub fn unlock_client(&mut self, id: ClientId) -> Result<(), ExchangeError> {
+        let client = self.clients.get_mut(&id).ok_or(AccountNotFound)?;
+        client.locked = false;
+
+        #[cfg(feature = "std")]
+        self.audit(format_args!("unlock client={}", id));
+
+        Ok(())
+    }
+
+    /// Adds `delta` to the client's available funds of the default asset, e.g.
+    /// as a manual correction. This is an administrative action that bypasses
+    /// the dispute process, so it isn't recorded as a transaction but as an
+    /// `Adjustment` with the given reason.
+    ///
+    /// It fails with `AccountLocked` for locked accounts, and with
+    /// `InsufficientFunds` if it would make the available funds negative
+    /// unless the exchange allows that.
+    pub fn adjust(
+        &mut self,
+        client: ClientId,
+        delta: M,
+        reason: &str,
+    ) -> Result<(), ExchangeError> {
+        if !delta.is_valid() {
+            return Err(InvalidAmount);
+        }
+
+        let account = self.clients.get_mut(&client).ok_or(AccountNotFound)?;
+        if account.locked {
+            return Err(AccountLocked);
+        }
+
+        let balances = account.balance_mut(DEFAULT_ASSET);
+        if !self.config.allow_negative_available && balances.funds_available + delta < M::zero() {
+            return Err(InsufficientFunds);
+        }
+
+        balances.funds_available += delta;
+        self.adjustments.push(Adjustment {
+            client,
+            delta,
+            reason: reason.to_owned(),
+        });
+
+        #[cfg(feature = "std")]
+        self.notify(client, DEFAULT_ASSET);
+        #[cfg(feature = "std")]
+        self.audit(format_args!(
+            "adjustment client={} delta={} reason={:?}",
+            client,
+            format_amount(delta, DEFAULT_SCALE),
+            reason
+        ));
+
+        Ok(())
+    }
+
+    /// The adjustments made so far, in the order they were made.
+    pub fn adjustments(&self) -> &[Adjustment<M>] {
+        &self.adjustments
+    }
+
+    /// The transfers made so far, in the order they were made.
+    pub fn transfers(&self) -> &[TransferRecord<M>] {
+        &self.transfers
+    }
+
+    /// Whether the client's account is locked. Unknown clients aren't.
+    pub fn is_locked(&self, id: ClientId) -> bool {
+        matches!(self.clients.get(&id), Some(client) if client.locked)
+    }
+
+    /// Whether a deposit, withdrawal, or transfer with the ID was recorded,
+    /// whatever state it's in now, so the ID can't be used again. With
+    /// per-client transaction IDs, any client's transaction with the ID counts.
+    pub fn contains_transaction(&self, tx: TxId) -> bool {
+        if self.config.per_client_tx_ids {
+            let used = |&(_, id): &TxKey| id == tx;
+            self.transactions.keys().any(used) || self.transfer_keys.iter().any(used)
+        } else {
+            self.is_taken((None, tx))
+        }
+    }
+
+    /// When the recorded deposit or withdrawal with the ID happened, if it was
+    /// processed with `process_at`. With per-client transaction IDs, it's the
+    /// first one processed with the ID.
+    pub fn transaction_timestamp(&self, tx: TxId) -> Option<i64> {
+        if self.config.per_client_tx_ids {
+            let key = self.transaction_order.iter().find(|&&(_, id)| id == tx)?;
+            self.transactions.get(key)?.timestamp
+        } else {
+            self.transactions.get(&(None, tx))?.timestamp
+        }
+    }
+
+    /// Whether the transaction could be disputed, i.e. it's completed and, if
+    /// disputes are limited to a session, from its client's current session. A
+    /// dispute may still fail for other reasons, like insufficient funds. With
+    /// per-client transaction IDs, any client's transaction with the ID counts.
+    pub fn is_disputable(&self, tx: TxId) -> bool {
+        let disputable = |record: &TransactionRecord<M>| {
+            record.status == Completed && !(self.config.session_disputes && record.prior_session)
+        };
+
+        if self.config.per_client_tx_ids {
+            self.transactions
+                .iter()
+                .any(|(&(_, id), record)| id == tx && disputable(record))
+        } else {
+            matches!(self.transactions.get(&(None, tx)), Some(record) if disputable(record))
+        }
+    }
+
+    /// Iterates over the IDs of all recorded transactions in the order they
+    /// were processed. With per-client transaction IDs the same ID can appear
+    /// more than once.
+    pub fn transactions_in_order(&self) -> impl Iterator<Item = TxId> + '_ {
+        self.transaction_order.iter().map(|&(_, tx)| tx)
+    }
+
+    /// Iterates over all recorded transactions and their current states in the
+    /// order they were processed.
+    pub fn transaction_states(&self) -> impl Iterator<Item = (TxId, TransactionStatus)> + '_ {
+        self.transaction_order
+            .iter()
+            .filter_map(move |key| Some((key.1, self.transactions.get(key)?.status)))
+    }
+
+    /// How much of the asset the client deposited and withdrew in total, all
+    /// zero for unknown clients. Disputes and adjustments don't change it.
+    pub fn flows(&self, client: ClientId, asset: &str) -> Flows<M> {
+        self.flows
+            .get(&client)
+            .and_then(|flows| flows.get(asset))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// The fees collected on withdrawals so far, see
+    /// `ExchangeBuilder::withdrawal_fee`.
+    pub fn total_fees(&self) -> M {
+        self.fees
+    }
+
+    /// How many disputes were successfully filed, including ones that were
+    /// since resolved or charged back.
+    pub fn total_disputes(&self) -> u64 {
+        self.disputes
+    }
+
+    /// Counts the recorded transactions in each state.
+    pub fn state_counts(&self) -> StateCounts {
+        let mut counts = StateCounts::default();
+
+        for record in self.transactions.values() {
+            match record.status {
+                Completed => counts.completed += 1,
+                Disputed => counts.disputed += 1,
+                Resolved => counts.resolved += 1,
+                ChargedBack => counts.charged_back += 1,
+            }
+        }
+
+        counts
+    }
+
+    /// Lists the IDs of the client's transactions that are currently disputed,
+    /// and so holding funds, in the order they were processed.
+    pub fn held_transactions(&self, client: ClientId) -> Vec<TxId> {
+        self.transaction_order
+            .iter()
+            .filter_map(|key| {
+                let record = self.transactions.get(key)?;
+                if record.client == client && record.status == Disputed {
+                    Some(key.1)
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Lists the IDs of all the client's deposits and withdrawals, whatever
+    /// state they're in, in the order they were processed.
+    pub fn client_transactions(&self, client: ClientId) -> Vec<TxId> {
+        self.client_transactions
+            .get(&client)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Resolves the disputes that have been open longer than the dispute window
+    /// as of `now`, in seconds since the Unix epoch, releasing their held
+    /// funds. A dispute processed with `process_at` is dated by its timestamp,
+    /// any other by the latest time given to either, so call this as time
+    /// passes, e.g. with each transaction's timestamp. Disputes opened before
+    /// any time was given count as opened at the first time given. Every call
+    /// goes over all the recorded transactions.
+    ///
+    /// Returns the IDs of the expired transactions in the order they were
+    /// processed. Nothing expires if there is no dispute window.
+    pub fn expire_disputes(&mut self, now: i64) -> Vec<TxId> {
+        self.now = Some(self.now.map_or(now, |previous| previous.max(now)));
+        let window = match self.config.dispute_window {
+            Some(window) => window,
+            None => return Vec::new(),
+        };
+
+        let mut expired = Vec::new();
+        for key in &self.transaction_order {
+            let record = match self.transactions.get_mut(key) {
+                Some(record) if record.status == Disputed => record,
+                _ => continue,
+            };
+            let disputed_at = *record.disputed_at.get_or_insert(now);
+            if now.saturating_sub(disputed_at) < window {
+                continue;
+            }
+
+            record.status = Resolved;
+            if let Some(client) = self.clients.get_mut(&record.client) {
+                release(client.balance_mut(&record.asset), record.amount);
+            }
+            expired.push(*key);
+        }
+
+        // the last dispute expiring settles held funds like a resolve does
+        for key in &expired {
+            if let Some(record) = self.transactions.get(key) {
+                let (client, asset) = (record.client, record.asset.clone());
+                self.clear_held_residue(client, &asset);
+            }
+        }
+
+        #[cfg(feature = "std")]
+        for key in &expired {
+            let (client, amount, asset) = match self.transactions.get(key) {
+                Some(record) => (record.client, record.amount.abs(), record.asset.clone()),
+                None => continue,
+            };
+            self.notify(client, &asset);
+            self.audit(format_args!(
+                "expire client={} tx={} released={}",
+                client,
+                key.1,
+                format_amount(amount, DEFAULT_SCALE)
+            ));
+        }
+
+        expired.into_iter().map(|(_, tx)| tx).collect()
+    }
+
+    /// Forgets all clients, transactions, transfers, and adjustments so the
+    /// exchange can be reused, e.g. for another independent file. Its
+    /// policies, filter, event sender, and audit writer are kept, and so is the
+    /// memory already allocated.
+    pub fn clear(&mut self) {
+        self.transactions.clear();
+        self.transaction_order.clear();
+        self.client_transactions.clear();
+        self.adjustments.clear();
+        self.transfers.clear();
+        self.transfer_keys.clear();
+        self.clients.clear();
+        self.flows.clear();
+        self.disputes = 0;
+        self.fees = M::zero();
+        self.now = None;
+    }
+
+    /// Zeroes every client's balances and replays them from the recorded
+    /// transactions and their current states. Each transaction is applied to
+    /// the client it was recorded for. Locked flags are left as they are.
+    ///
+    /// This is a consistency tool: after processing, the balances should
+    /// already match what the history says they are.
+    pub fn recompute_balances(&mut self) {
+        for client in self.clients.values_mut() {
+            for balances in client.balances.values_mut() {
+                *balances = Balances::new();
+            }
+        }
+
+        for (id, replayed) in self.replay() {
+            let client = self.clients.get_or_default(id);
+            for (asset, balances) in replayed {
+                *client.balance_mut(&asset) = balances;
+            }
+        }
+    }
+
+    /// Lists the clients whose balances don't match what replaying the
+    /// recorded transactions gives, sorted by ID. Amounts within the
+    /// exchange's epsilon of each other are considered equal, so float
+    /// rounding alone doesn't cause a mismatch.
+    pub fn reconcile(&self) -> Vec<ClientId> {
+        let replayed = self.replay();
+        let empty = Map::new();
+        let zero = Balances::new();
+        let epsilon = self.config.epsilon;
+
+        let mut mismatched = self
+            .clients
+            .iter()
+            .filter(|(id, client)| {
+                let replayed = replayed.get(id).unwrap_or(&empty);
+                let assets = client.balances.keys().chain(replayed.keys());
+
+                assets.into_iter().any(|asset| {
+                    let actual = client.balance(asset).unwrap_or(&zero);
+                    let expected = replayed.get(asset).unwrap_or(&zero);
+
+                    !approx_eq(actual.funds_available, expected.funds_available, epsilon)
+                        || !approx_eq(actual.funds_held, expected.funds_held, epsilon)
+                })
+            })
+            .map(|(&id, _)| id)
+            .collect::<Vec<_>>();
+        mismatched.sort_unstable();
+
+        mismatched
+    }
+
+    /// Lists the clients whose accounts differ in the other exchange, sorted by
+    /// ID, e.g. to compare a recomputed state against the live one. A client
+    /// missing from one exchange counts as having no funds and not being
+    /// locked there. Amounts within this exchange's epsilon of each other are
+    /// considered equal.
+    pub fn diff(&self, other: &Exchange<M>) -> Vec<ClientDiff<M>> {
+        let empty = Client::new();
+        let zero = Balances::new();
+        let epsilon = self.config.epsilon;
+
+        let mut ids = self
+            .clients
+            .iter()
+            .chain(other.clients.iter())
+            .map(|(&id, _)| id)
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+        ids.dedup();
+
+        ids.into_iter()
+            .filter_map(|id| {
+                let before = self.clients.get(&id).unwrap_or(&empty);
+                let after = other.clients.get(&id).unwrap_or(&empty);
+                let mut balances = Map::new();
+
+                for asset in before.balances.keys().chain(after.balances.keys()) {
+                    let old = before.balance(asset).unwrap_or(&zero);
+                    let new = after.balance(asset).unwrap_or(&zero);
+
+                    if !approx_eq(old.funds_available, new.funds_available, epsilon)
+                        || !approx_eq(old.funds_held, new.funds_held, epsilon)
+                    {
+                        let delta = Balances {
+                            funds_available: new.funds_available - old.funds_available,
+                            funds_held: new.funds_held - old.funds_held,
+                        };
+                        balances.insert(asset.clone(), delta);
+                    }
+                }
+
+                let locked = if before.locked != after.locked {
+                    Some(after.locked)
+                } else {
+                    None
+                };
+
+                if balances.is_empty() && locked.is_none() {
+                    None
+                } else {
+                    Some(ClientDiff {
+                        client: id,
+                        balances,
+                        locked,
+                    })
+                }
+            })
+            .collect()
+    }
+
+    /// Computes every client's balances from the recorded transactions and
+    /// their current states, plus any transfers and adjustments.
+    fn replay(&self) -> Map<ClientId, Map<Asset, Balances<M>>> {
+        let mut clients = Map::<_, Map<_, Balances<M>>>::new();
+
+        for key in &self.transaction_order {
+            let record = match self.transactions.get(key) {
+                Some(record) => record,
+                None => continue,
+            };
+            let balances = clients
+                .entry(record.client)
+                .or_default()
+                .entry(record.asset.clone())
+                .or_default();
+
+            balances.funds_available += record.amount - record.fee;
+            match record.status {
+                Completed => {}
+                Disputed => hold(balances, record.amount),
+                Resolved => {
+                    hold(balances, record.amount);
+                    release(balances, record.amount);
+                }
+                ChargedBack => {
+                    hold(balances, record.amount);
+                    reverse(balances, record.amount);
+                }
+            }
+        }
+
+        for transfer in &self.transfers {
+            let moves = [
+                (transfer.from, -transfer.amount),
+                (transfer.to, transfer.amount),
+            ];
+            for &(client, amount) in moves.iter() {
+                clients
+                    .entry(client)
+                    .or_default()
+                    .entry(transfer.asset.clone())
+                    .or_default()
+                    .funds_available += amount;
+            }
+        }
+
+        for adjustment in &self.adjustments {
+            let balances = clients
+                .entry(adjustment.client)
+                .or_default()
+                .entry(DEFAULT_ASSET.to_owned())
+                .or_default();
+            balances.funds_available += adjustment.delta;
+        }
+
+        clients
+    }
+
+    fn deposit(
+        &mut self,
+        tx: TxId,
+        client: ClientId,
+        asset: &str,
+        amount: M,
+    ) -> Result<ProcessOutcome<M>, ExchangeError> {
+        validate_amount(amount, self.config.max_amount)?;
+
+        let id = client;
+        let key = self.key(id, tx);
+        // checked before the account is created, so a failed deposit never
+        // counts as a client's initial one
+        if self.is_taken(key) {
+            if self.is_resubmission(key, id, asset, amount) {
+                return Ok(ProcessOutcome::Deposited {
+                    new_available: self.available(id, asset),
+                });
+            }
+            return Err(TransactionAlreadyExists);
+        }
+        let client = account(&mut self.clients, client, true, self.config.max_clients)?;
+        if self.config.enforce_locks && client.locked {
+            return Err(AccountLocked);
+        }
+
+        if self.config.record_transactions {
+            self.transactions.insert(
+                key,
+                TransactionRecord {
+                    client: id,
+                    asset: asset.to_owned(),
+                    amount,
+                    status: Completed,
+                    timestamp: None,
+                    disputed_at: None,
+                    prior_session: false,
+                    fee: M::zero(),
+                },
+            );
+
+            self.transaction_order.push(key);
+            self.client_transactions.entry(id).or_default().push(tx);
+        }
+        let balances = client.balance_mut(asset);
+        balances.funds_available += amount;
+        self.flows
+            .entry(id)
+            .or_default()
+            .entry(asset.to_owned())
+            .or_default()
+            .deposited += amount;
+
+        Ok(ProcessOutcome::Deposited {
+            new_available: balances.funds_available,
+        })
+    }
+
+    fn withdraw(
+        &mut self,
+        tx: TxId,
+        client: ClientId,
+        asset: &str,
+        amount: M,
+    ) -> Result<ProcessOutcome<M>, ExchangeError> {
+        validate_amount(amount, self.config.max_amount)?;
+
+        let id = client;
+        let key = self.key(id, tx);
+        // checked before the funds, which the original may have used up
+        if self.is_resubmission(key, id, asset, -amount) {
+            return Ok(ProcessOutcome::Withdrawn {
+                new_available: self.available(id, asset),
+            });
+        }
+        if self.is_taken(key) {
+            return Err(TransactionAlreadyExists);
+        }
+
+        // everything is checked before the account is touched, so a failed
+        // withdrawal never creates a client or an asset's balances
+        let create = !self.config.strict_accounts;
+        check_account(&self.clients, id, create, self.config.max_clients)?;
+        let current = self.clients.get(&id);
+        if self.config.enforce_locks && matches!(current, Some(client) if client.locked) {
+            return Err(AccountLocked);
+        }
+        let available = current
+            .and_then(|client| client.balance(asset))
+            .map_or(M::zero(), |balances| balances.funds_available);
+
+        let policy = self.config.withdrawal_fee;
+        let fee_on = |amount| policy.map_or(M::zero(), |fee: Fee<M>| fee.on(amount));
+        let (amount, fee) = if available >= amount + fee_on(amount) {
+            (amount, fee_on(amount))
+        } else if self.config.partial_withdrawals {
+            // the fee comes out of what's available too, and it can't round
+            // past it
+            let available = positive(available);
+            let amount = positive(policy.map_or(available, |fee| fee.most_from(available)));
+            let fee = fee_on(amount);
+            let fee = if amount + fee > available {
+                available - amount
+            } else {
+                fee
+            };
+            (amount, fee)
+        } else {
+            return Err(InsufficientFunds);
+        };
+        if amount < self.config.min_withdrawal {
+            return Err(AmountBelowMinimum);
+        }
+
+        if self.config.record_transactions {
+            self.transactions.insert(
+                key,
+                TransactionRecord {
+                    client: id,
+                    asset: asset.to_owned(),
+                    amount: -amount,
+                    status: Completed,
+                    timestamp: None,
+                    disputed_at: None,
+                    prior_session: false,
+                    fee,
+                },
+            );
+
+            self.transaction_order.push(key);
+            self.client_transactions.entry(id).or_default().push(tx);
+        }
+        let client = account(&mut self.clients, id, create, self.config.max_clients)?;
+        let balances = client.balance_mut(asset);
+        balances.funds_available -= amount + fee;
+        self.fees += fee;
+        self.flows
+            .entry(id)
+            .or_default()
+            .entry(asset.to_owned())
+            .or_default()
+            .withdrawn += amount;
+
+        Ok(ProcessOutcome::Withdrawn {
+            new_available: balances.funds_available,
+        })
+    }
+
+    /// Moves the amount from one client's available funds to another's. The
+    /// sender must have an unlocked account with enough funds, and the
+    /// recipient's account is created if needed. Transfers are kept apart from
+    /// the recorded transactions, so they can't be disputed.
+    fn transfer(
+        &mut self,
+        tx: TxId,
+        from: ClientId,
+        to: ClientId,
+        asset: Asset,
+        amount: M,
+    ) -> Result<ProcessOutcome<M>, ExchangeError> {
+        validate_amount(amount, self.config.max_amount)?;
+        if self.config.forbid_client_zero && to == 0 {
+            return Err(ReservedClientId);
+        }
+        let key = self.key(from, tx);
+        if self.is_taken(key) {
+            return Err(TransactionAlreadyExists);
+        }
+
+        // everything is checked before either account changes, so a failed
+        // transfer leaves both as they were
+        let sender = self.clients.get(&from).ok_or(AccountNotFound)?;
+        if sender.locked {
+            return Err(AccountLocked);
+        }
+        let available = sender
+            .balance(&asset)
+            .map_or(M::zero(), |balances| balances.funds_available);
+        if available < amount {
+            return Err(InsufficientFunds);
+        }
+        let recipient = account(&mut self.clients, to, true, self.config.max_clients)?;
+        if self.config.enforce_locks && recipient.locked {
+            return Err(AccountLocked);
+        }
+
+        recipient.balance_mut(&asset).funds_available += amount;
+        let balances = self.clients.get_or_default(from).balance_mut(&asset);
+        balances.funds_available -= amount;
+        let new_available = balances.funds_available;
+
+        self.transfer_keys.insert(key);
+        self.transfers.push(TransferRecord {
+            tx,
+            from,
+            to,
+            asset,
+            amount,
+        });
+
+        Ok(ProcessOutcome::Transferred { new_available })
+    }
+
+    fn dispute(&mut self, tx: TxId, client: ClientId) -> Result<ProcessOutcome<M>, ExchangeError> {
+        let key = self.key(client, tx);
+        let record = self.transactions.get_mut(&key).ok_or(TransactionNotFound)?;
+        if record.client != client {
+            return Err(ClientMismatch);
+        }
+        if self.config.session_disputes && record.prior_session {
+            return Err(TransactionFromPriorSession);
+        }
+        let create = !self.config.strict_accounts;
+        let client = account(&mut self.clients, client, create, self.config.max_clients)?;
+
+        match record.status {
+            Completed => {}
+            ChargedBack => return Err(TransactionChargedBack),
+            Disputed | Resolved => return Err(TransactionAlreadyDisputed),
+        }
+
+        let balances = client.balance_mut(&record.asset);
+        if !self.config.allow_negative_available
+            && balances.funds_available < positive(record.amount)
+        {
+            return Err(InsufficientFunds);
+        }
+        if self.config.guard_negative_disputes
+            && record.amount < M::zero()
+            && balances.funds_available < M::zero()
+        {
+            return Err(AvailableFundsNegative);
+        }
+
+        record.status = Disputed;
+        record.disputed_at = self.now;
+        hold(balances, record.amount);
+        self.disputes += 1;
+
+        Ok(ProcessOutcome::Held {
+            amount: record.amount.abs(),
+        })
+    }
+
+    fn resolve(&mut self, tx: TxId, client: ClientId) -> Result<ProcessOutcome<M>, ExchangeError> {
+        let id = client;
+        let key = self.key(client, tx);
+        let record = self.transactions.get_mut(&key).ok_or(TransactionNotFound)?;
+        if record.client != client {
+            return Err(ClientMismatch);
+        }
+        let create = !self.config.strict_accounts;
+        let client = account(&mut self.clients, client, create, self.config.max_clients)?;
+
+        match record.status {
+            Disputed => {}
+            Resolved => return Err(TransactionAlreadyResolved),
+            Completed | ChargedBack => return Err(TransactionNotDisputed),
+        }
+
+        record.status = Resolved;
+        release(client.balance_mut(&record.asset), record.amount);
+        let amount = record.amount.abs();
+        let asset = record.asset.clone();
+        self.clear_held_residue(id, &asset);
+
+        Ok(ProcessOutcome::Released { amount })
+    }
+
+    fn chargeback(
+        &mut self,
+        tx: TxId,
+        client: ClientId,
+    ) -> Result<ProcessOutcome<M>, ExchangeError> {
+        let id = client;
+        let key = self.key(client, tx);
+        let record = self.transactions.get_mut(&key).ok_or(TransactionNotFound)?;
+        if record.client != client {
+            return Err(ClientMismatch);
+        }
+        let create = !self.config.strict_accounts;
+        let client = account(&mut self.clients, client, create, self.config.max_clients)?;
+
+        if record.status != Disputed {
+            return Err(TransactionNotDisputed);
+        }
+
+        record.status = ChargedBack;
+        reverse(client.balance_mut(&record.asset), record.amount);
+        client.locked = true;
+        let asset = record.asset.clone();
+        self.clear_held_residue(id, &asset);
+
+        Ok(ProcessOutcome::Locked)
+    }
+
+    /// Zeroes the client's held funds of the asset if they're within epsilon
+    /// of zero and none of its transactions in the asset are still disputed,
+    /// so settling the last dispute doesn't leave float rounding residue held.
+    fn clear_held_residue(&mut self, client: ClientId, asset: &str) {
+        let zero = M::zero();
+        let held = match self.clients.get(&client).and_then(|c| c.balance(asset)) {
+            Some(balances) => balances.funds_held,
+            None => return,
+        };
+        if held == zero || !approx_eq(held, zero, self.config.epsilon) {
+            return;
+        }
+
+        let txs = self
+            .client_transactions
+            .get(&client)
+            .map_or(&[][..], Vec::as_slice);
+        let disputed = txs.iter().any(|&tx| {
+            matches!(
+                self.transactions.get(&self.key(client, tx)),
+                Some(record) if record.status == Disputed && record.asset == asset
+            )
+        });
+
+        if !disputed {
+            if let Some(client) = self.clients.get_mut(&client) {
+                client.balance_mut(asset).funds_held = zero;
+            }
+        }
+    }
+
+    /// Starts a new session for the client, so its transactions so far are
+    /// from a prior one.
+    fn start_session(&mut self, client: ClientId) -> Result<ProcessOutcome<M>, ExchangeError> {
+        let per_client = self.config.per_client_tx_ids;
+        let txs = match self.client_transactions.get(&client) {
+            Some(txs) => txs,
+            None => return Ok(ProcessOutcome::SessionStarted),
+        };
+
+        for &tx in txs.iter().rev() {
+            match self.transactions.get_mut(&tx_key(per_client, client, tx)) {
+                // anything older was marked by an earlier boundary
+                Some(record) if record.prior_session => break,
+                Some(record) => record.prior_session = true,
+                None => {}
+            }
+        }
+
+        Ok(ProcessOutcome::SessionStarted)
+    }
+
+    /// Freezes or unfreezes the client's account.
+    fn set_locked(
+        &mut self,
+        client: ClientId,
+        locked: bool,
+    ) -> Result<ProcessOutcome<M>, ExchangeError> {
+        let client = self.clients.get_mut(&client).ok_or(AccountNotFound)?;
+        client.locked = locked;
+
+        Ok(if locked {
+            ProcessOutcome::Frozen
+        } else {
+            ProcessOutcome::Unfrozen
+        })
+    }
+
+    /// Whether a transaction or transfer already took the key's ID.
+    fn is_taken(&self, key: TxKey) -> bool {
+        self.transactions.contains_key(&key) || self.transfer_keys.contains(&key)
+    }
+
+    /// Whether the exchange is idempotent and the transaction recorded under
+    /// the key has the same client, asset, and amount, negative for
+    /// withdrawals.
+    fn is_resubmission(&self, key: TxKey, client: ClientId, asset: &str, amount: M) -> bool {
+        self.config.idempotent
+            && matches!(
+                self.transactions.get(&key),
+                Some(record) if record.client == client && record.asset == asset && record.amount == amount
+            )
+    }
+
+    /// The client's available funds of the asset, zero if it has none.
+    fn available(&self, client: ClientId, asset: &str) -> M {
+        self.clients
+            .get(&client)
+            .and_then(|client| client.balance(asset))
+            .map_or(M::zero(), |balances| balances.funds_available)
+    }
+
+    /// The key the client's transaction is recorded under.
+    fn key(&self, client: ClientId, tx: TxId) -> TxKey {
+        tx_key(self.config.per_client_tx_ids, client, tx)
+    }
+}
+
+/// The key a client's transaction is recorded under, with the client only if
+/// transaction IDs are unique per client.
+fn tx_key(per_client: bool, client: ClientId, tx: TxId) -> TxKey {
+    if per_client {
+        (Some(client), tx)
+    } else {
+        (None, tx)
+    }
+}
+
+/// The audit fields describing the transaction, see
+/// `Exchange::set_audit_writer`.
+#[cfg(feature = "std")]
+fn audit_fields<M: Amount>(transaction: &Transaction<M>) -> String {
+    use Transaction::*;
+
+    let mut fields = format!(
+        "{} client={} tx={}",
+        transaction.kind(),
+        transaction.client(),
+        transaction.tx_id()
+    );
+    if let Transfer(_, to, ..) = transaction {
+        fields += &format!(" to={}", to);
+    }
+    match transaction {
+        Deposit(_, _, asset, _) | Withdrawal(_, _, asset, _) | Transfer(_, _, _, asset, _)
+            if asset != DEFAULT_ASSET =>
+        {
+            fields += &format!(" asset={}", asset);
+        }
+        _ => {}
+    }
+    if let Some(amount) = transaction.amount() {
+        fields += &format!(" amount={}", format_amount(amount, DEFAULT_SCALE));
+    }
+
+    fields
+}
+
+/// The audit fields describing what processing a transaction did.
+#[cfg(feature = "std")]
+fn outcome_fields<M: Amount>(outcome: &ProcessOutcome<M>) -> String {
+    use ProcessOutcome::*;
+
+    match outcome {
+        Deposited { new_available }
+        | Withdrawn { new_available }
+        | Transferred { new_available } => {
+            format!(
+                " available={}",
+                format_amount(*new_available, DEFAULT_SCALE)
+            )
+        }
+        Held { amount } => format!(" held={}", format_amount(*amount, DEFAULT_SCALE)),
+        Released { amount } => format!(" released={}", format_amount(*amount, DEFAULT_SCALE)),
+        Locked => String::from(" locked=true"),
+        SessionStarted => String::new(),
+        Frozen => String::from(" locked=true"),
+        Unfrozen => String::from(" locked=false"),
+    }
+}
+
+/// Holds the funds of a disputed transaction. A disputed deposit holds the
+/// funds it made available. A disputed withdrawal's funds already left the
+/// account, so they are held pending its reversal without touching the
+/// available funds.
+fn hold<M: Amount>(balances: &mut Balances<M>, amount: M) {
+    balances.funds_available -= positive(amount);
+    balances.funds_held += amount.abs();
+}
+
+/// Releases the held funds of a disputed transaction that stands, so a
+/// deposit's funds are available again and a withdrawal's are no longer
+/// expected back.
+fn release<M: Amount>(balances: &mut Balances<M>, amount: M) {
+    balances.funds_available += positive(amount);
+    balances.funds_held -= amount.abs();
+}
+
+/// Reverses a disputed transaction, so a deposit's held funds are removed and a
+/// withdrawal's are returned.
+fn reverse<M: Amount>(balances: &mut Balances<M>, amount: M) {
+    balances.funds_held -= amount.abs();
+    balances.funds_available -= amount - positive(amount);
+}
+
+/// The amount if it's positive, or zero otherwise.
+fn positive<M: Amount>(amount: M) -> M {
+    if amount > M::zero() {
+        amount
+    } else {
+        M::zero()
+    }
+}
+
+/// Gets the client's account, creating an empty one if allowed and there's room
+/// for another client.
+fn account<M: Amount>(
+    clients: &mut Clients<M>,
+    id: ClientId,
+    create: bool,
+    max_clients: Option<usize>,
+) -> Result<&mut Client<M>, ExchangeError> {
+    check_account(clients, id, create, max_clients)?;
+
+    Ok(clients.get_or_default(id))
+}
+
+/// Checks that `account` would get the client's account, without creating it.
+fn check_account<M: Amount>(
+    clients: &Clients<M>,
+    id: ClientId,
+    create: bool,
+    max_clients: Option<usize>,
+) -> Result<(), ExchangeError> {
+    if !clients.contains_key(&id) {
+        if !create {
+            return Err(AccountNotFound);
+        }
+        if matches!(max_clients, Some(max) if clients.len() >= max) {
+            return Err(TooManyClients);
+        }
+    }
+
+    Ok(())
+}
+
+/// The clients' accounts, kept in whichever map the exchange was built with.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "bincode", derive(Serialize, Deserialize))]
+enum Clients<M> {
+    Unordered(Map<ClientId, Client<M>>),
+    Ordered(BTreeMap<ClientId, Client<M>>),
+}
+
+/// An iterator over the clients, in ID order if they're kept ordered.
+type ClientsIter<'a, M> = Box<dyn Iterator<Item = (&'a ClientId, &'a Client<M>)> + 'a>;
+
+impl<M: Amount> Clients<M> {
+    fn new(ordered: bool) -> Clients<M> {
+        if ordered {
+            Clients::Ordered(BTreeMap::new())
+        } else {
+            Clients::Unordered(Map::new())
+        }
+    }
+
+    fn get(&self, id: &ClientId) -> Option<&Client<M>> {
+        match self {
+            Clients::Unordered(clients) => clients.get(id),
+            Clients::Ordered(clients) => clients.get(id),
+        }
+    }
+
+    fn get_mut(&mut self, id: &ClientId) -> Option<&mut Client<M>> {
+        match self {
+            Clients::Unordered(clients) => clients.get_mut(id),
+            Clients::Ordered(clients) => clients.get_mut(id),
+        }
+    }
+
+    /// Gets the client's account, creating an empty one if needed.
+    fn get_or_default(&mut self, id: ClientId) -> &mut Client<M> {
+        match self {
+            Clients::Unordered(clients) => clients.entry(id).or_default(),
+            Clients::Ordered(clients) => clients.entry(id).or_default(),
+        }
+    }
+
+    fn contains_key(&self, id: &ClientId) -> bool {
+        self.get(id).is_some()
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Clients::Unordered(clients) => clients.len(),
+            Clients::Ordered(clients) => clients.len(),
+        }
+    }
+
+    fn clear(&mut self) {
+        match self {
+            Clients::Unordered(clients) => clients.clear(),
+            Clients::Ordered(clients) => clients.clear(),
+        }
+    }
+
+    /// Makes room for more clients, if the map can.
+    #[cfg(feature = "std")]
+    fn reserve(&mut self, additional: usize) {
+        match self {
+            Clients::Unordered(clients) => clients.reserve(additional),
+            Clients::Ordered(_) => {}
+        }
+    }
+
+    /// How many clients fit without growing, if the map has a capacity.
+    #[cfg(all(test, feature = "std"))]
+    fn capacity(&self) -> Option<usize> {
+        match self {
+            Clients::Unordered(clients) => Some(clients.capacity()),
+            Clients::Ordered(_) => None,
+        }
+    }
+
+    fn iter(&self) -> ClientsIter<'_, M> {
+        match self {
+            Clients::Unordered(clients) => Box::new(clients.iter()),
+            Clients::Ordered(clients) => Box::new(clients.iter()),
+        }
+    }
+
+    fn values_mut(&mut self) -> Box<dyn Iterator<Item = &mut Client<M>> + '_> {
+        match self {
+            Clients::Unordered(clients) => Box::new(clients.values_mut()),
+            Clients::Ordered(clients) => Box::new(clients.values_mut()),
+        }
+    }
+}
+
+impl<M: Amount> core::ops::Index<&ClientId> for Clients<M> {
+    type Output = Client<M>;
+
+    fn index(&self, id: &ClientId) -> &Client<M> {
+        self.get(id).expect("no client with that ID")
+    }
+}
+
+/// Rejects amounts that would corrupt balances if applied or that are over the
+/// limit.
+fn validate_amount<M: Amount>(amount: M, max: Option<M>) -> Result<(), ExchangeError> {
+    if !amount.is_valid() || amount < M::zero() {
+        return Err(InvalidAmount);
+    }
+    if matches!(M::exact_limit(), Some(limit) if amount >= limit) {
+        return Err(AmountLosesPrecision);
+    }
+
+    match max {
+        Some(max) if amount > max => Err(AmountExceedsLimit),
+        _ => Ok(()),
+    }
+}
+
+impl<M: Amount> Default for Exchange<M> {
+    fn default() -> Exchange<M> {
+        ExchangeBuilder::default().build()
+    }
+}
+
+/// Everything `Exchange::snapshot_bin` saves: the recorded transactions and
+/// their order, the clients, the adjustments and transfers, and the policies.
+#[cfg(feature = "bincode")]
+type Snapshot<M> = (
+    Map<TxKey, TransactionRecord<M>>,
+    Vec<TxKey>,
+    Map<ClientId, Vec<TxId>>,
+    Vec<Adjustment<M>>,
+    Vec<TransferRecord<M>>,
+    Clients<M>,
+    Map<ClientId, Map<Asset, Flows<M>>>,
+    u64,
+    M,
+    Option<i64>,
+    Config<M>,
+);
+
+#[cfg(feature = "bincode")]
+impl<M: Amount + Serialize + DeserializeOwned> Exchange<M> {
+    /// Saves the exchange's state in a compact binary format, to be restored
+    /// with `restore_bin`. The filter, event sender, and audit writer aren't
+    /// saved.
+    pub fn snapshot_bin(&self) -> Vec<u8> {
+        let snapshot = (
+            &self.transactions,
+            &self.transaction_order,
+            &self.client_transactions,
+            &self.adjustments,
+            &self.transfers,
+            &self.clients,
+            &self.flows,
+            self.disputes,
+            self.fees,
+            self.now,
+            &self.config,
+        );
+
+        bincode::serialize(&snapshot).expect("the state is always serializable")
+    }
+
+    /// Restores an exchange saved with `snapshot_bin`, with the same state and
+    /// policies but without a filter, event sender, or audit writer.
+    pub fn restore_bin(bytes: &[u8]) -> Result<Exchange<M>, bincode::Error> {
+        let (
+            transactions,
+            transaction_order,
+            client_transactions,
+            adjustments,
+            transfers,
+            clients,
+            flows,
+            disputes,
+            fees,
+            now,
+            config,
+        ): Snapshot<M> = bincode::deserialize(bytes)?;
+
+        // the transfers' keys follow from the transfers, so they aren't saved
+        let transfer_keys = transfers
+            .iter()
+            .map(|transfer| tx_key(config.per_client_tx_ids, transfer.from, transfer.tx))
+            .collect();
+
+        Ok(Exchange {
+            transactions,
+            transaction_order,
+            client_transactions,
+            adjustments,
+            transfers,
+            transfer_keys,
+            clients,
+            flows,
+            disputes,
+            fees,
+            config,
+            filter: None,
+            now,
+            #[cfg(feature = "std")]
+            events: None,
+            #[cfg(feature = "std")]
+            audit: None,
+            #[cfg(feature = "std")]
+            audit_batch: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::DEFAULT_ASSET;
+    use crate::money::money;
+
+    #[test]
+    fn deposit_succeeds_and_adds_funds_with_unique_tx_id() {
+        let mut exchange = Exchange::new();
+
+        assert!(exchange.deposit(5, 1, DEFAULT_ASSET, money("1.0")).is_ok());
+
+        let balances = exchange.clients[&1].balance(DEFAULT_ASSET).unwrap();
+        assert_eq!(balances.funds_held, money("0.0"));
+        assert_eq!(balances.funds_available, money("1.0"));
+    }
+
+    #[test]
+    fn deposit_fails_with_non_unique_tx_id() {
+        let mut exchange = Exchange::new();
+
+        exchange.deposit(5, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        assert_eq!(
+            exchange.deposit(5, 1, DEFAULT_ASSET, money("2.0")),
+            Err(TransactionAlreadyExists)
+        );
+
+        exchange
+            .withdraw(6, 1, DEFAULT_ASSET, money("1.0"))
+            .unwrap();
+        assert_eq!(
+            exchange.deposit(6, 1, DEFAULT_ASSET, money("2.0")),
+            Err(TransactionAlreadyExists)
+        );
+    }
+
+    #[test]
+    fn deposit_fails_reusing_tx_id_of_resolved_transaction() {
+        let mut exchange = Exchange::new();
+
+        exchange.deposit(5, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.dispute(5, 1).unwrap();
+        exchange.resolve(5, 1).unwrap();
+        assert_eq!(
+            exchange.deposit(5, 1, DEFAULT_ASSET, money("2.0")),
+            Err(TransactionAlreadyExists)
+        );
+
+        let balances = exchange.clients[&1].balance(DEFAULT_ASSET).unwrap();
+        assert_eq!(balances.funds_available, money("1.0"));
+        assert_eq!(balances.funds_held, money("0.0"));
+    }
+
+    #[test]
+    fn deposit_keeps_separate_balances_for_each_asset() {
+        let mut exchange = Exchange::new();
+
+        exchange.deposit(5, 1, "BTC", money("1.0")).unwrap();
+        exchange.deposit(6, 1, "ETH", money("2.0")).unwrap();
+
+        let client = exchange.clients.get(&1).unwrap();
+        let btc = client.balance("BTC").unwrap();
+        assert_eq!(btc.funds_held, money("0.0"));
+        assert_eq!(btc.funds_available, money("1.0"));
+        let eth = client.balance("ETH").unwrap();
+        assert_eq!(eth.funds_held, money("0.0"));
+        assert_eq!(eth.funds_available, money("2.0"));
+        assert_eq!(client.balance(DEFAULT_ASSET), None);
+    }
+
+    #[test]
+    fn deposit_fails_with_invalid_amount() {
+        let mut exchange = Exchange::new();
+
+        assert_eq!(
+            exchange.deposit(5, 1, DEFAULT_ASSET, money("-1.0")),
+            Err(InvalidAmount)
+        );
+
+        let mut exchange = Exchange::<f32>::default();
+        assert_eq!(
+            exchange.deposit(6, 1, DEFAULT_ASSET, f32::NAN),
+            Err(InvalidAmount)
+        );
+        assert_eq!(
+            exchange.deposit(7, 1, DEFAULT_ASSET, f32::INFINITY),
+            Err(InvalidAmount)
+        );
+    }
+
+    #[test]
+    fn deposit_fails_if_amount_loses_precision() {
+        let mut exchange = Exchange::new();
+
+        // two of these would overflow a decimal balance
+        assert_eq!(
+            exchange.deposit(1, 1, DEFAULT_ASSET, money("79228162514264337593543950335")),
+            Err(AmountLosesPrecision)
+        );
+        exchange
+            .deposit(2, 1, DEFAULT_ASSET, money("16777215.0"))
+            .unwrap();
+
+        // the literal would be rounded before it even gets to the exchange
+        let mut exchange = Exchange::<f32>::default();
+        let amount = "16777217".parse().unwrap();
+        assert_eq!(
+            exchange.deposit(1, 1, DEFAULT_ASSET, amount),
+            Err(AmountLosesPrecision)
+        );
+    }
+
+    #[test]
+    fn deposit_fails_if_amount_exceeds_limit() {
+        let mut exchange = ExchangeBuilder::new().max_amount(money("100.0")).build();
+
+        assert!(exchange
+            .deposit(5, 1, DEFAULT_ASSET, money("100.0"))
+            .is_ok());
+        assert_eq!(
+            exchange.deposit(6, 1, DEFAULT_ASSET, money("100.5")),
+            Err(AmountExceedsLimit)
+        );
+
+        let balances = exchange.clients[&1].balance(DEFAULT_ASSET).unwrap();
+        assert_eq!(balances.funds_available, money("100.0"));
+    }
+
+    #[test]
+    fn deposit_fails_for_new_client_beyond_max_clients() {
+        let mut exchange = ExchangeBuilder::new().max_clients(2).build();
+
+        exchange.deposit(5, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.deposit(6, 2, DEFAULT_ASSET, money("1.0")).unwrap();
+        assert_eq!(
+            exchange.deposit(7, 3, DEFAULT_ASSET, money("1.0")),
+            Err(TooManyClients)
+        );
+        assert_eq!(
+            exchange.withdraw(8, 3, DEFAULT_ASSET, money("0.0")),
+            Err(TooManyClients)
+        );
+
+        // existing clients are unaffected
+        assert!(exchange.deposit(9, 1, DEFAULT_ASSET, money("1.0")).is_ok());
+        assert!(!exchange.clients.contains_key(&3));
+        assert!(!exchange.transactions.contains_key(&(None, 7)));
+    }
+
+    #[test]
+    fn withdraw_succeeds_and_pulls_funds_with_unique_tx_id() {
+        let mut exchange = Exchange::new();
+
+        exchange.deposit(5, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        assert!(exchange.withdraw(6, 1, DEFAULT_ASSET, money("1.0")).is_ok());
+
+        let balances = exchange.clients[&1].balance(DEFAULT_ASSET).unwrap();
+        assert_eq!(balances.funds_held, money("0.0"));
+        assert_eq!(balances.funds_available, money("0.0"));
+    }
+
+    #[test]
+    fn idempotent_exchange_skips_identical_resubmissions() {
+        let mut exchange = ExchangeBuilder::new().idempotent(true).build();
+
+        exchange.deposit(5, 1, DEFAULT_ASSET, money("4.0")).unwrap();
+        exchange
+            .withdraw(6, 1, DEFAULT_ASSET, money("4.0"))
+            .unwrap();
+        assert_eq!(
+            exchange.deposit(5, 1, DEFAULT_ASSET, money("4.0")),
+            Ok(ProcessOutcome::Deposited {
+                new_available: money("0.0")
+            })
+        );
+        assert_eq!(
+            exchange.withdraw(6, 1, DEFAULT_ASSET, money("4.0")),
+            Ok(ProcessOutcome::Withdrawn {
+                new_available: money("0.0")
+            })
+        );
+
+        assert_eq!(
+            exchange.clients[&1].funds(),
+            (money("0.0"), money("0.0"), money("0.0"))
+        );
+        assert_eq!(
+            exchange.transactions_in_order().collect::<Vec<_>>(),
+            vec![5, 6]
+        );
+    }
+
+    #[test]
+    fn idempotent_exchange_rejects_conflicting_resubmissions() {
+        let mut exchange = ExchangeBuilder::new().idempotent(true).build();
+
+        exchange.deposit(5, 1, DEFAULT_ASSET, money("4.0")).unwrap();
+        assert_eq!(
+            exchange.deposit(5, 1, DEFAULT_ASSET, money("3.0")),
+            Err(TransactionAlreadyExists)
+        );
+        assert_eq!(
+            exchange.deposit(5, 2, DEFAULT_ASSET, money("4.0")),
+            Err(TransactionAlreadyExists)
+        );
+        assert_eq!(
+            exchange.deposit(5, 1, "BTC", money("4.0")),
+            Err(TransactionAlreadyExists)
+        );
+        assert_eq!(
+            exchange.withdraw(5, 1, DEFAULT_ASSET, money("4.0")),
+            Err(TransactionAlreadyExists)
+        );
+
+        assert_eq!(
+            exchange.clients[&1].funds(),
+            (money("4.0"), money("0.0"), money("4.0"))
+        );
+    }
+
+    #[test]
+    fn withdraw_fails_with_non_unique_id() {
+        let mut exchange = Exchange::new();
+
+        exchange.deposit(5, 1, DEFAULT_ASSET, money("4.0")).unwrap();
+        assert_eq!(
+            exchange.withdraw(5, 1, DEFAULT_ASSET, money("1.0")),
+            Err(TransactionAlreadyExists)
+        );
+
+        exchange
+            .withdraw(6, 1, DEFAULT_ASSET, money("2.0"))
+            .unwrap();
+        assert_eq!(
+            exchange.withdraw(6, 1, DEFAULT_ASSET, money("1.0")),
+            Err(TransactionAlreadyExists)
+        );
+    }
+
+    #[test]
+    fn withdraw_fails_if_client_has_insufficient_funds() {
+        let mut exchange = Exchange::new();
+
+        exchange.deposit(5, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        assert_eq!(
+            exchange.withdraw(6, 1, DEFAULT_ASSET, money("2.0")),
+            Err(InsufficientFunds)
+        );
+
+        let balances = exchange.clients[&1].balance(DEFAULT_ASSET).unwrap();
+        assert_eq!(balances.funds_held, money("0.0"));
+        assert_eq!(balances.funds_available, money("1.0"));
+    }
+
+    #[test]
+    fn withdraw_failing_leaves_clients_unchanged() {
+        let mut exchange = Exchange::new_ordered();
+
+        exchange.deposit(5, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        let before = exchange.clients_sorted();
+
+        assert_eq!(
+            exchange.withdraw(6, 2, DEFAULT_ASSET, money("1.0")),
+            Err(InsufficientFunds)
+        );
+        assert_eq!(
+            exchange.withdraw(7, 1, "BTC", money("1.0")),
+            Err(InsufficientFunds)
+        );
+        assert_eq!(
+            exchange.withdraw(5, 3, DEFAULT_ASSET, money("1.0")),
+            Err(TransactionAlreadyExists)
+        );
+
+        assert_eq!(exchange.clients_sorted(), before);
+    }
+
+    #[test]
+    fn transfer_moves_funds_between_clients() {
+        let mut exchange = Exchange::new();
+
+        exchange.deposit(5, 1, DEFAULT_ASSET, money("3.0")).unwrap();
+        let outcome = exchange.process(Transaction::Transfer(
+            1,
+            2,
+            6,
+            DEFAULT_ASSET.into(),
+            money("1.0"),
+        ));
+        assert_eq!(
+            outcome,
+            Ok(ProcessOutcome::Transferred {
+                new_available: money("2.0")
+            })
+        );
+
+        assert_eq!(
+            exchange.clients[&1].funds(),
+            (money("2.0"), money("0.0"), money("2.0"))
+        );
+        assert_eq!(
+            exchange.clients[&2].funds(),
+            (money("1.0"), money("0.0"), money("1.0"))
+        );
+        assert_eq!(exchange.transfers().len(), 1);
+        assert_eq!(exchange.reconcile(), vec![]);
+    }
+
+    #[test]
+    fn transfer_fails_if_sender_has_insufficient_funds() {
+        let mut exchange = Exchange::new();
+
+        exchange.deposit(5, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        assert_eq!(
+            exchange.process(Transaction::Transfer(
+                1,
+                2,
+                6,
+                DEFAULT_ASSET.into(),
+                money("2.0")
+            )),
+            Err(InsufficientFunds)
+        );
+
+        assert_eq!(
+            exchange.clients[&1].funds(),
+            (money("1.0"), money("0.0"), money("1.0"))
+        );
+        assert!(!exchange.clients.contains_key(&2));
+        assert!(exchange.transfers().is_empty());
+    }
+
+    #[test]
+    fn transfer_fails_reusing_a_transaction_id() {
+        let mut exchange = Exchange::new();
+
+        exchange.deposit(5, 1, DEFAULT_ASSET, money("3.0")).unwrap();
+        let transfer = |tx| Transaction::Transfer(1, 2, tx, DEFAULT_ASSET.into(), money("1.0"));
+        assert!(exchange.process(transfer(6)).is_ok());
+
+        assert_eq!(exchange.process(transfer(6)), Err(TransactionAlreadyExists));
+        assert_eq!(exchange.process(transfer(5)), Err(TransactionAlreadyExists));
+        assert_eq!(
+            exchange.deposit(6, 2, DEFAULT_ASSET, money("1.0")),
+            Err(TransactionAlreadyExists)
+        );
+
+        assert_eq!(exchange.transfers().len(), 1);
+        assert!(exchange.contains_transaction(6));
+        assert_eq!(
+            exchange.clients[&1].funds(),
+            (money("2.0"), money("0.0"), money("2.0"))
+        );
+    }
+
+    #[test]
+    fn transfer_fails_from_locked_account() {
+        let mut exchange = Exchange::new();
+
+        exchange.deposit(5, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.deposit(6, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.dispute(6, 1).unwrap();
+        exchange.chargeback(6, 1).unwrap();
+        assert_eq!(
+            exchange.process(Transaction::Transfer(
+                1,
+                2,
+                7,
+                DEFAULT_ASSET.into(),
+                money("1.0")
+            )),
+            Err(AccountLocked)
+        );
+
+        assert_eq!(
+            exchange.clients[&1].funds(),
+            (money("1.0"), money("0.0"), money("1.0"))
+        );
+    }
+
+    #[test]
+    fn withdraw_takes_available_funds_with_partial_withdrawals() {
+        let mut exchange = ExchangeBuilder::new().partial_withdrawals(true).build();
+
+        exchange.deposit(5, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        let outcome = exchange.withdraw(6, 1, DEFAULT_ASSET, money("3.0"));
+        assert_eq!(
+            outcome,
+            Ok(ProcessOutcome::Withdrawn {
+                new_available: money("0.0")
+            })
+        );
+
+        // the recorded amount is what was actually withdrawn
+        exchange.dispute(6, 1).unwrap();
+        exchange.chargeback(6, 1).unwrap();
+
+        let balances = exchange.clients[&1].balance(DEFAULT_ASSET).unwrap();
+        assert_eq!(balances.funds_available, money("1.0"));
+        assert_eq!(balances.funds_held, money("0.0"));
+    }
+
+    #[test]
+    fn withdraw_fails_with_invalid_amount() {
+        let mut exchange = Exchange::new();
+
+        exchange.deposit(5, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        assert_eq!(
+            exchange.withdraw(6, 1, DEFAULT_ASSET, money("-1.0")),
+            Err(InvalidAmount)
+        );
+
+        let balances = exchange.clients[&1].balance(DEFAULT_ASSET).unwrap();
+        assert_eq!(balances.funds_available, money("1.0"));
+
+        let mut exchange = Exchange::<f32>::default();
+        exchange.deposit(5, 1, DEFAULT_ASSET, 1.0).unwrap();
+        assert_eq!(
+            exchange.withdraw(7, 1, DEFAULT_ASSET, f32::NAN),
+            Err(InvalidAmount)
+        );
+    }
+
+    #[test]
+    fn withdraw_fails_if_amount_exceeds_limit() {
+        let mut exchange = ExchangeBuilder::new().max_amount(money("100.0")).build();
+
+        exchange
+            .deposit(5, 1, DEFAULT_ASSET, money("100.0"))
+            .unwrap();
+        exchange
+            .deposit(6, 1, DEFAULT_ASSET, money("100.0"))
+            .unwrap();
+        assert!(exchange
+            .withdraw(7, 1, DEFAULT_ASSET, money("50.0"))
+            .is_ok());
+        assert_eq!(
+            exchange.withdraw(8, 1, DEFAULT_ASSET, money("150.0")),
+            Err(AmountExceedsLimit)
+        );
+
+        let balances = exchange.clients[&1].balance(DEFAULT_ASSET).unwrap();
+        assert_eq!(balances.funds_available, money("150.0"));
+    }
+
+    #[test]
+    fn withdraw_creates_account_for_unknown_client_by_default() {
+        let mut exchange = Exchange::new();
+
+        assert_eq!(
+            exchange.withdraw(5, 1, DEFAULT_ASSET, money("1.0")),
+            Err(InsufficientFunds)
+        );
+        assert!(!exchange.clients.contains_key(&1));
+
+        // only a withdrawal that goes through creates the account
+        assert!(exchange.withdraw(6, 1, DEFAULT_ASSET, money("0.0")).is_ok());
+        assert!(exchange.clients.contains_key(&1));
+    }
+
+    #[test]
+    fn withdraw_fails_for_unknown_client_with_strict_accounts() {
+        let mut exchange = ExchangeBuilder::new().strict_accounts(true).build();
+
+        assert_eq!(
+            exchange.withdraw(5, 1, DEFAULT_ASSET, money("1.0")),
+            Err(AccountNotFound)
+        );
+        assert!(!exchange.clients.contains_key(&1));
+
+        exchange.deposit(6, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        assert!(exchange.withdraw(7, 1, DEFAULT_ASSET, money("1.0")).is_ok());
+    }
+
+    #[test]
+    fn strict_accounts_require_a_successful_initial_deposit() {
+        let mut exchange = ExchangeBuilder::new().strict_accounts(true).build();
+
+        exchange.deposit(1, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        assert_eq!(
+            exchange.deposit(1, 2, DEFAULT_ASSET, money("1.0")),
+            Err(TransactionAlreadyExists)
+        );
+        assert!(!exchange.clients.contains_key(&2));
+        assert_eq!(
+            exchange.withdraw(2, 2, DEFAULT_ASSET, money("1.0")),
+            Err(AccountNotFound)
+        );
+        assert_eq!(exchange.dispute(1, 2), Err(ClientMismatch));
+    }
+
+    #[test]
+    fn dispute_succeeds_and_holds_funds_on_existing_transaction() {
+        let mut exchange = Exchange::new();
+
+        exchange.deposit(5, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        assert!(exchange.dispute(5, 1).is_ok());
+
+        let balances = exchange.clients[&1].balance(DEFAULT_ASSET).unwrap();
+        assert_eq!(balances.funds_held, money("1.0"));
+        assert_eq!(balances.funds_available, money("0.0"));
+    }
+
+    #[test]
+    fn dispute_only_holds_funds_of_the_disputed_asset() {
+        let mut exchange = Exchange::new();
+
+        exchange.deposit(5, 1, "BTC", money("1.0")).unwrap();
+        exchange.deposit(6, 1, "ETH", money("2.0")).unwrap();
+        assert!(exchange.dispute(6, 1).is_ok());
+
+        let client = exchange.clients.get(&1).unwrap();
+        let btc = client.balance("BTC").unwrap();
+        assert_eq!(btc.funds_held, money("0.0"));
+        assert_eq!(btc.funds_available, money("1.0"));
+        let eth = client.balance("ETH").unwrap();
+        assert_eq!(eth.funds_held, money("2.0"));
+        assert_eq!(eth.funds_available, money("0.0"));
+    }
+
+    #[test]
+    fn dispute_of_withdrawal_holds_funds_pending_reversal() {
+        let mut exchange = Exchange::new();
+
+        exchange
+            .deposit(5, 1, DEFAULT_ASSET, money("100.0"))
+            .unwrap();
+        exchange
+            .withdraw(6, 1, DEFAULT_ASSET, money("100.0"))
+            .unwrap();
+        assert_eq!(
+            exchange.dispute(6, 1),
+            Ok(ProcessOutcome::Held {
+                amount: money("100.0")
+            })
+        );
+
+        let balances = exchange.clients[&1].balance(DEFAULT_ASSET).unwrap();
+        assert_eq!(balances.funds_available, money("0.0"));
+        assert_eq!(balances.funds_held, money("100.0"));
+        assert_eq!(balances.funds_total(), money("100.0"));
+    }
+
+    #[test]
+    fn dispute_fails_if_transaction_doesnt_exist() {
+        let mut exchange = Exchange::new();
+
+        assert_eq!(exchange.dispute(5, 1), Err(TransactionNotFound));
+    }
+
+    #[test]
+    fn dispute_fails_for_unknown_client_with_strict_accounts() {
+        let mut exchange = ExchangeBuilder::new().strict_accounts(true).build();
+
+        exchange.deposit(5, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        // another client's transaction is never theirs to dispute
+        assert_eq!(exchange.dispute(5, 2), Err(ClientMismatch));
+        assert!(!exchange.clients.contains_key(&2));
+    }
+
+    #[test]
+    fn dispute_fails_if_it_would_make_available_funds_negative() {
+        let mut exchange = Exchange::new();
+
+        exchange.deposit(5, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange
+            .withdraw(6, 1, DEFAULT_ASSET, money("1.0"))
+            .unwrap();
+        assert_eq!(exchange.dispute(5, 1), Err(InsufficientFunds));
+
+        let balances = exchange.clients[&1].balance(DEFAULT_ASSET).unwrap();
+        assert_eq!(balances.funds_held, money("0.0"));
+        assert_eq!(balances.funds_available, money("0.0"));
+    }
+
+    #[test]
+    fn dispute_succeeds_with_negative_available_funds_if_allowed() {
+        let mut exchange = ExchangeBuilder::new()
+            .allow_negative_available(true)
+            .build();
+
+        exchange.deposit(5, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange
+            .withdraw(6, 1, DEFAULT_ASSET, money("1.0"))
+            .unwrap();
+        assert!(exchange.dispute(5, 1).is_ok());
+
+        let balances = exchange.clients[&1].balance(DEFAULT_ASSET).unwrap();
+        assert_eq!(balances.funds_held, money("1.0"));
+        assert_eq!(balances.funds_available, money("-1.0"));
+    }
+
+    #[test]
+    fn dispute_of_withdrawal_fails_with_negative_available_funds_if_guarded() {
+        let mut exchange = ExchangeBuilder::new()
+            .allow_negative_available(true)
+            .guard_negative_disputes(true)
+            .build();
+
+        exchange.deposit(5, 1, DEFAULT_ASSET, money("2.0")).unwrap();
+        exchange
+            .withdraw(6, 1, DEFAULT_ASSET, money("1.5"))
+            .unwrap();
+        exchange.dispute(5, 1).unwrap();
+        assert_eq!(exchange.dispute(6, 1), Err(AvailableFundsNegative));
+
+        let balances = exchange.clients[&1].balance(DEFAULT_ASSET).unwrap();
+        assert_eq!(balances.funds_held, money("2.0"));
+        assert_eq!(balances.funds_available, money("-1.5"));
+    }
+
+    #[test]
+    fn dispute_fails_if_transaction_is_already_disputed() {
+        let mut exchange = Exchange::new();
+
+        exchange.deposit(5, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.dispute(5, 1).unwrap();
+        assert_eq!(exchange.dispute(5, 1), Err(TransactionAlreadyDisputed));
+    }
+
+    #[test]
+    fn dispute_fails_if_transaction_is_already_resolved() {
+        let mut exchange = Exchange::new();
+
+        exchange.deposit(5, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.dispute(5, 1).unwrap();
+        exchange.resolve(5, 1).unwrap();
+        assert_eq!(exchange.dispute(5, 1), Err(TransactionAlreadyDisputed));
+
+        exchange.deposit(6, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.dispute(6, 1).unwrap();
+        exchange.chargeback(6, 1).unwrap();
+        assert_eq!(exchange.dispute(6, 1), Err(TransactionChargedBack));
+    }
+
+    #[test]
+    fn dispute_fails_if_withdrawal_was_charged_back() {
+        let mut exchange = Exchange::new();
+
+        exchange.deposit(1, 1, DEFAULT_ASSET, money("2.0")).unwrap();
+        exchange
+            .withdraw(2, 1, DEFAULT_ASSET, money("1.0"))
+            .unwrap();
+        exchange.dispute(2, 1).unwrap();
+        exchange.chargeback(2, 1).unwrap();
+
+        assert_eq!(exchange.dispute(2, 1), Err(TransactionChargedBack));
+        let balances = exchange.clients[&1].balance(DEFAULT_ASSET).unwrap();
+        assert_eq!(balances.funds_available, money("2.0"));
+        assert_eq!(balances.funds_held, money("0.0"));
+    }
+
+    #[test]
+    fn resolve_succeeds_and_releases_held_funds_on_disputed_transaction() {
+        let mut exchange = Exchange::new();
+
+        exchange.deposit(5, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.dispute(5, 1).unwrap();
+        assert!(exchange.resolve(5, 1).is_ok());
+
+        let balances = exchange.clients[&1].balance(DEFAULT_ASSET).unwrap();
+        assert_eq!(balances.funds_held, money("0.0"));
+        assert_eq!(balances.funds_available, money("1.0"));
+    }
+
+    #[test]
+    fn resolve_of_disputed_withdrawal_drops_held_funds() {
+        let mut exchange = Exchange::new();
+
+        exchange
+            .deposit(5, 1, DEFAULT_ASSET, money("100.0"))
+            .unwrap();
+        exchange
+            .withdraw(6, 1, DEFAULT_ASSET, money("100.0"))
+            .unwrap();
+        exchange.dispute(6, 1).unwrap();
+        assert!(exchange.resolve(6, 1).is_ok());
+
+        let balances = exchange.clients[&1].balance(DEFAULT_ASSET).unwrap();
+        assert_eq!(balances.funds_available, money("0.0"));
+        assert_eq!(balances.funds_held, money("0.0"));
+    }
+
+    #[test]
+    fn dispute_resolve_and_chargeback_fail_for_another_clients_transaction() {
+        let mut exchange = Exchange::new();
+
+        exchange.deposit(1, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.deposit(2, 2, DEFAULT_ASSET, money("1.0")).unwrap();
+        assert_eq!(exchange.dispute(1, 2), Err(ClientMismatch));
+
+        exchange.dispute(1, 1).unwrap();
+        assert_eq!(exchange.resolve(1, 2), Err(ClientMismatch));
+        assert_eq!(exchange.chargeback(1, 2), Err(ClientMismatch));
+
+        let balances = exchange.clients[&1].balance(DEFAULT_ASSET).unwrap();
+        assert_eq!(balances.funds_held, money("1.0"));
+        assert!(!exchange.is_locked(1));
+        assert!(!exchange.is_locked(2));
+    }
+
+    #[test]
+    fn session_disputes_only_allows_disputes_within_the_session() {
+        let mut exchange = ExchangeBuilder::new().session_disputes(true).build();
+
+        exchange.deposit(1, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.deposit(2, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.deposit(3, 2, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.dispute(2, 1).unwrap();
+        assert_eq!(
+            exchange.process(Transaction::SessionBoundary(1, 4)),
+            Ok(ProcessOutcome::SessionStarted)
+        );
+        exchange.deposit(5, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+
+        assert_eq!(exchange.dispute(1, 1), Err(TransactionFromPriorSession));
+        // a dispute from before the boundary can still be settled
+        assert!(exchange.resolve(2, 1).is_ok());
+        assert!(exchange.dispute(5, 1).is_ok());
+        // other clients' sessions are unaffected
+        assert!(exchange.dispute(3, 2).is_ok());
+
+        exchange
+            .process(Transaction::SessionBoundary(1, 6))
+            .unwrap();
+        assert_eq!(exchange.dispute(2, 1), Err(TransactionFromPriorSession));
+
+        let balances = exchange.clients[&1].balance(DEFAULT_ASSET).unwrap();
+        assert_eq!(balances.funds_available, money("2.0"));
+        assert_eq!(balances.funds_held, money("1.0"));
+    }
+
+    #[test]
+    fn disputes_ignore_sessions_by_default() {
+        let mut exchange = Exchange::new();
+
+        exchange.deposit(1, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange
+            .process(Transaction::SessionBoundary(1, 2))
+            .unwrap();
+
+        assert!(exchange.dispute(1, 1).is_ok());
+    }
+
+    #[test]
+    fn settling_the_last_dispute_leaves_exactly_nothing_held() {
+        let mut exchange = Exchange::new();
+
+        exchange.deposit(1, 1, DEFAULT_ASSET, money("0.1")).unwrap();
+        exchange.deposit(2, 1, DEFAULT_ASSET, money("0.2")).unwrap();
+        exchange.deposit(3, 1, DEFAULT_ASSET, money("0.1")).unwrap();
+        exchange.deposit(4, 1, DEFAULT_ASSET, money("0.2")).unwrap();
+
+        exchange.dispute(1, 1).unwrap();
+        exchange.dispute(2, 1).unwrap();
+        exchange.resolve(1, 1).unwrap();
+        exchange.resolve(2, 1).unwrap();
+        let balances = exchange.clients[&1].balance(DEFAULT_ASSET).unwrap();
+        assert_eq!(balances.funds_held, money("0.0"));
+
+        exchange.dispute(3, 1).unwrap();
+        exchange.dispute(4, 1).unwrap();
+        exchange.resolve(3, 1).unwrap();
+        exchange.chargeback(4, 1).unwrap();
+        let balances = exchange.clients[&1].balance(DEFAULT_ASSET).unwrap();
+        assert_eq!(balances.funds_held, money("0.0"));
+    }
+
+    #[test]
+    fn disputes_fail_without_recorded_transactions() {
+        let mut exchange = ExchangeBuilder::new().record_transactions(false).build();
+
+        exchange.deposit(1, 1, DEFAULT_ASSET, money("3.0")).unwrap();
+        exchange.deposit(2, 1, DEFAULT_ASSET, money("1.5")).unwrap();
+        exchange
+            .withdraw(3, 1, DEFAULT_ASSET, money("1.0"))
+            .unwrap();
+
+        assert_eq!(exchange.dispute(1, 1), Err(TransactionNotFound));
+        assert_eq!(exchange.resolve(1, 1), Err(TransactionNotFound));
+        assert_eq!(exchange.chargeback(3, 1), Err(TransactionNotFound));
+        assert_eq!(exchange.transaction_states().count(), 0);
+
+        let balances = exchange.clients[&1].balance(DEFAULT_ASSET).unwrap();
+        assert_eq!(balances.funds_available, money("3.5"));
+        assert_eq!(balances.funds_held, money("0.0"));
+        assert!(!exchange.is_locked(1));
+    }
+
+    #[test]
+    fn withdraw_charges_flat_fee() {
+        let mut exchange = ExchangeBuilder::new()
+            .withdrawal_fee(Fee::Flat(money("0.5")))
+            .build();
+
+        exchange.deposit(5, 1, DEFAULT_ASSET, money("3.0")).unwrap();
+        assert_eq!(
+            exchange.withdraw(6, 1, DEFAULT_ASSET, money("2.0")),
+            Ok(ProcessOutcome::Withdrawn {
+                new_available: money("0.5")
+            })
+        );
+        assert_eq!(
+            exchange.withdraw(7, 1, DEFAULT_ASSET, money("0.25")),
+            Err(InsufficientFunds)
+        );
+
+        assert_eq!(exchange.total_fees(), money("0.5"));
+        assert_eq!(exchange.reconcile(), vec![]);
+    }
+
+    #[test]
+    fn withdraw_charges_fee_rate() {
+        let mut exchange = ExchangeBuilder::new()
+            .withdrawal_fee(Fee::Rate(money("0.25")))
+            .build();
+
+        exchange
+            .deposit(5, 1, DEFAULT_ASSET, money("10.0"))
+            .unwrap();
+        exchange
+            .withdraw(6, 1, DEFAULT_ASSET, money("4.0"))
+            .unwrap();
+        exchange
+            .withdraw(7, 1, DEFAULT_ASSET, money("2.0"))
+            .unwrap();
+
+        assert_eq!(
+            exchange.clients[&1].funds(),
+            (money("2.5"), money("0.0"), money("2.5"))
+        );
+        assert_eq!(exchange.total_fees(), money("1.5"));
+
+        // a chargeback returns the amount but not the fee
+        exchange.dispute(7, 1).unwrap();
+        exchange.chargeback(7, 1).unwrap();
+        assert_eq!(
+            exchange.clients[&1].funds(),
+            (money("4.5"), money("0.0"), money("4.5"))
+        );
+        assert_eq!(exchange.reconcile(), vec![]);
+    }
+
+    #[test]
+    fn withdrawal_fee_ignores_negative_fees() {
+        for &fee in &[Fee::Rate(money("-1.0")), Fee::Flat(money("-0.5"))] {
+            let mut exchange = ExchangeBuilder::new()
+                .withdrawal_fee(fee)
+                .partial_withdrawals(true)
+                .build();
+
+            exchange.deposit(5, 1, DEFAULT_ASSET, money("2.0")).unwrap();
+            assert_eq!(
+                exchange.withdraw(6, 1, DEFAULT_ASSET, money("1.0")),
+                Ok(ProcessOutcome::Withdrawn {
+                    new_available: money("1.0")
+                })
+            );
+            assert_eq!(
+                exchange.withdraw(7, 1, DEFAULT_ASSET, money("5.0")),
+                Ok(ProcessOutcome::Withdrawn {
+                    new_available: money("0.0")
+                })
+            );
+            assert_eq!(exchange.total_fees(), money("0.0"));
+        }
+    }
+
+    #[test]
+    fn partial_withdrawal_leaves_room_for_fee_rate() {
+        let mut exchange = ExchangeBuilder::new()
+            .withdrawal_fee(Fee::Rate(money("0.25")))
+            .partial_withdrawals(true)
+            .build();
+
+        exchange
+            .deposit(5, 1, DEFAULT_ASSET, money("10.0"))
+            .unwrap();
+        assert_eq!(
+            exchange.withdraw(6, 1, DEFAULT_ASSET, money("20.0")),
+            Ok(ProcessOutcome::Withdrawn {
+                new_available: money("0.0")
+            })
+        );
+
+        // 8 withdrawn with a fee of a quarter of that
+        assert_eq!(exchange.total_fees(), money("2.0"));
+        assert_eq!(exchange.flows(1, DEFAULT_ASSET).withdrawn, money("8.0"));
+        exchange.dispute(6, 1).unwrap();
+        assert_eq!(
+            exchange.clients[&1].funds(),
+            (money("0.0"), money("8.0"), money("8.0"))
+        );
+        assert_eq!(exchange.reconcile(), vec![]);
+    }
+
+    #[test]
+    fn partial_withdrawal_leaves_room_for_flat_fee() {
+        let mut exchange = ExchangeBuilder::new()
+            .withdrawal_fee(Fee::Flat(money("0.5")))
+            .partial_withdrawals(true)
+            .build();
+
+        exchange.deposit(5, 1, DEFAULT_ASSET, money("3.0")).unwrap();
+        exchange
+            .withdraw(6, 1, DEFAULT_ASSET, money("5.0"))
+            .unwrap();
+
+        assert_eq!(exchange.total_fees(), money("0.5"));
+        assert_eq!(
+            exchange.clients[&1].funds(),
+            (money("0.0"), money("0.0"), money("0.0"))
+        );
+        exchange.dispute(6, 1).unwrap();
+        assert_eq!(
+            exchange.clients[&1].funds(),
+            (money("0.0"), money("2.5"), money("2.5"))
+        );
+    }
+
+    #[test]
+    fn withdraw_fails_below_min_withdrawal() {
+        let mut exchange = ExchangeBuilder::new().min_withdrawal(money("1.0")).build();
+
+        exchange.deposit(1, 1, DEFAULT_ASSET, money("5.0")).unwrap();
+        assert_eq!(
+            exchange.withdraw(2, 1, DEFAULT_ASSET, money("0.5")),
+            Err(AmountBelowMinimum)
+        );
+        assert!(exchange.withdraw(3, 1, DEFAULT_ASSET, money("1.0")).is_ok());
+        assert!(exchange.withdraw(4, 1, DEFAULT_ASSET, money("2.5")).is_ok());
+
+        let balances = exchange.clients[&1].balance(DEFAULT_ASSET).unwrap();
+        assert_eq!(balances.funds_available, money("1.5"));
+    }
+
+    #[test]
+    fn partial_withdrawal_fails_if_less_than_min_withdrawal_is_available() {
+        let mut exchange = ExchangeBuilder::new()
+            .min_withdrawal(money("1.0"))
+            .partial_withdrawals(true)
+            .build();
+
+        exchange.deposit(1, 1, DEFAULT_ASSET, money("0.5")).unwrap();
+        assert_eq!(
+            exchange.withdraw(2, 1, DEFAULT_ASSET, money("3.0")),
+            Err(AmountBelowMinimum)
+        );
+
+        let balances = exchange.clients[&1].balance(DEFAULT_ASSET).unwrap();
+        assert_eq!(balances.funds_available, money("0.5"));
+    }
+
+    #[test]
+    fn client_zero_is_valid_by_default() {
+        let mut exchange = Exchange::new();
+
+        assert!(exchange
+            .process(Transaction::Deposit(
+                0,
+                1,
+                DEFAULT_ASSET.into(),
+                money("1.0")
+            ))
+            .is_ok());
+        assert!(exchange.process(Transaction::Dispute(0, 1)).is_ok());
+    }
+
+    #[test]
+    fn client_zero_fails_with_forbid_client_zero() {
+        let mut exchange = ExchangeBuilder::new().forbid_client_zero(true).build();
+
+        assert_eq!(
+            exchange.process(Transaction::Deposit(
+                0,
+                1,
+                DEFAULT_ASSET.into(),
+                money("1.0")
+            )),
+            Err(ReservedClientId)
+        );
+        assert_eq!(
+            exchange.process(Transaction::Withdrawal(
+                0,
+                2,
+                DEFAULT_ASSET.into(),
+                money("1.0")
+            )),
+            Err(ReservedClientId)
+        );
+        assert!(exchange
+            .process(Transaction::Deposit(
+                1,
+                3,
+                DEFAULT_ASSET.into(),
+                money("1.0")
+            ))
+            .is_ok());
+        assert_eq!(exchange.clients().count(), 1);
+    }
+
+    #[test]
+    fn resolve_fails_if_transaction_doesnt_exists() {
+        let mut exchange = Exchange::new();
+
+        assert_eq!(exchange.resolve(5, 1), Err(TransactionNotFound));
+    }
+
+    #[test]
+    fn resolve_fails_if_transaction_is_not_disputed() {
+        let mut exchange = Exchange::new();
+
+        exchange.deposit(5, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        assert_eq!(exchange.resolve(5, 1), Err(TransactionNotDisputed));
+    }
+
+    #[test]
+    fn resolve_fails_if_transaction_already_resolved() {
+        let mut exchange = Exchange::new();
+
+        exchange.deposit(5, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.dispute(5, 1).unwrap();
+        exchange.resolve(5, 1).unwrap();
+        assert_eq!(exchange.resolve(5, 1), Err(TransactionAlreadyResolved));
+
+        exchange.deposit(6, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.dispute(6, 1).unwrap();
+        exchange.chargeback(6, 1).unwrap();
+        assert_eq!(exchange.resolve(6, 1), Err(TransactionNotDisputed));
+    }
+
+    #[test]
+    fn chargeback_succeeds_and_removes_held_funds_and_locks_client_on_disputed_transaction() {
+        let mut exchange = Exchange::new();
+
+        exchange.deposit(5, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.dispute(5, 1).unwrap();
+        assert!(exchange.chargeback(5, 1).is_ok());
+
+        let client = exchange.clients.get(&1).unwrap();
+        let balances = client.balance(DEFAULT_ASSET).unwrap();
+        assert_eq!(balances.funds_held, money("0.0"));
+        assert_eq!(balances.funds_available, money("0.0"));
+        assert!(client.locked);
+    }
+
+    #[test]
+    fn chargeback_of_disputed_withdrawal_returns_funds_and_locks_client() {
+        let mut exchange = Exchange::new();
+
+        exchange
+            .deposit(5, 1, DEFAULT_ASSET, money("100.0"))
+            .unwrap();
+        exchange
+            .withdraw(6, 1, DEFAULT_ASSET, money("100.0"))
+            .unwrap();
+        exchange.dispute(6, 1).unwrap();
+        assert!(exchange.chargeback(6, 1).is_ok());
+
+        let client = exchange.clients.get(&1).unwrap();
+        let balances = client.balance(DEFAULT_ASSET).unwrap();
+        assert_eq!(balances.funds_available, money("100.0"));
+        assert_eq!(balances.funds_held, money("0.0"));
+        assert!(client.locked);
+    }
+
+    #[test]
+    fn chargeback_fails_if_transaction_doesnt_exists() {
+        let mut exchange = Exchange::new();
+
+        assert_eq!(exchange.chargeback(5, 1), Err(TransactionNotFound));
+    }
+
+    #[test]
+    fn chargeback_fails_if_transaction_is_not_disputed() {
+        let mut exchange = Exchange::new();
+
+        exchange.deposit(5, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        assert_eq!(exchange.chargeback(5, 1), Err(TransactionNotDisputed));
+    }
+
+    #[test]
+    fn chargeback_fails_if_transaction_already_resolved() {
+        let mut exchange = Exchange::new();
+
+        exchange.deposit(5, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.dispute(5, 1).unwrap();
+        exchange.resolve(5, 1).unwrap();
+        assert_eq!(exchange.chargeback(5, 1), Err(TransactionNotDisputed));
+
+        exchange.deposit(6, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.dispute(6, 1).unwrap();
+        exchange.chargeback(6, 1).unwrap();
+        assert_eq!(exchange.chargeback(6, 1), Err(TransactionNotDisputed));
+    }
+
+    #[test]
+    fn process_reports_outcome_of_deposit() {
+        let mut exchange = Exchange::new();
+
+        exchange
+            .process(Transaction::Deposit(
+                1,
+                5,
+                DEFAULT_ASSET.into(),
+                money("1.0"),
+            ))
+            .unwrap();
+        let outcome = exchange.process(Transaction::Deposit(
+            1,
+            6,
+            DEFAULT_ASSET.into(),
+            money("2.0"),
+        ));
+
+        assert_eq!(
+            outcome,
+            Ok(ProcessOutcome::Deposited {
+                new_available: money("3.0")
+            })
+        );
+    }
+
+    #[test]
+    fn process_reports_outcome_of_dispute_process() {
+        let mut exchange = Exchange::new();
+
+        exchange
+            .process(Transaction::Deposit(
+                1,
+                5,
+                DEFAULT_ASSET.into(),
+                money("1.0"),
+            ))
+            .unwrap();
+        let held = exchange.process(Transaction::Dispute(1, 5));
+        let locked = exchange.process(Transaction::Chargeback(1, 5));
+
+        assert_eq!(
+            held,
+            Ok(ProcessOutcome::Held {
+                amount: money("1.0")
+            })
+        );
+        assert_eq!(locked, Ok(ProcessOutcome::Locked));
+    }
+
+    #[test]
+    fn apply_atomic_applies_whole_batch() {
+        let buffer = SharedBuffer::default();
+        let mut exchange = Exchange::new();
+        exchange.set_audit_writer(Box::new(buffer.clone()));
+
+        let batch = vec![
+            Transaction::Deposit(1, 1, DEFAULT_ASSET.into(), money("2.0")),
+            Transaction::Withdrawal(1, 2, DEFAULT_ASSET.into(), money("0.5")),
+        ];
+        assert_eq!(exchange.apply_atomic(batch), Ok(()));
+
+        let balances = exchange.clients[&1].balance(DEFAULT_ASSET).unwrap();
+        assert_eq!(balances.funds_available, money("1.5"));
+        let audit = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        assert_eq!(
+            audit,
+            "deposit client=1 tx=1 amount=2.0000 available=2.0000\n\
+             withdrawal client=1 tx=2 amount=0.5000 available=1.5000\n"
+        );
+    }
+
+    #[test]
+    fn apply_atomic_rolls_back_batch_on_failure() {
+        let buffer = SharedBuffer::default();
+        let mut exchange = Exchange::new();
+        exchange.set_audit_writer(Box::new(buffer.clone()));
+        exchange
+            .process(Transaction::Deposit(
+                1,
+                1,
+                DEFAULT_ASSET.into(),
+                money("1.0"),
+            ))
+            .unwrap();
+
+        let batch = vec![
+            Transaction::Deposit(1, 2, DEFAULT_ASSET.into(), money("2.0")),
+            Transaction::Dispute(1, 1),
+            Transaction::Withdrawal(1, 3, DEFAULT_ASSET.into(), money("5.0")),
+        ];
+        assert_eq!(exchange.apply_atomic(batch), Err((2, InsufficientFunds)));
+
+        let balances = exchange.clients[&1].balance(DEFAULT_ASSET).unwrap();
+        assert_eq!(balances.funds_available, money("1.0"));
+        assert_eq!(balances.funds_held, money("0.0"));
+        assert_eq!(
+            exchange.transactions_in_order().collect::<Vec<_>>(),
+            vec![1]
+        );
+        assert_eq!(exchange.total_disputes(), 0);
+        assert!(exchange.deposit(2, 1, DEFAULT_ASSET, money("2.0")).is_ok());
+
+        // only the transaction from before the batch was audited
+        let audit = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        assert_eq!(
+            audit,
+            "deposit client=1 tx=1 amount=1.0000 available=1.0000\n"
+        );
+    }
+
+    #[test]
+    fn process_skips_transactions_rejected_by_filter() {
+        let mut exchange = Exchange::new();
+        exchange.set_filter(|transaction| match transaction {
+            Transaction::Withdrawal(_, _, _, amount) => *amount <= money("100.0"),
+            _ => true,
+        });
+
+        exchange
+            .process(Transaction::Deposit(
+                1,
+                5,
+                DEFAULT_ASSET.into(),
+                money("500.0"),
+            ))
+            .unwrap();
+        let small = exchange.process(Transaction::Withdrawal(
+            1,
+            6,
+            DEFAULT_ASSET.into(),
+            money("100.0"),
+        ));
+        let large = exchange.process(Transaction::Withdrawal(
+            1,
+            7,
+            DEFAULT_ASSET.into(),
+            money("101.0"),
+        ));
+
+        assert_eq!(
+            small,
+            Ok(ProcessOutcome::Withdrawn {
+                new_available: money("400.0")
+            })
+        );
+        assert_eq!(large, Err(Rejected));
+        assert_eq!(
+            exchange.transactions_in_order().collect::<Vec<_>>(),
+            vec![5, 6]
+        );
+    }
+
+    /// A writer whose output can still be read after it's boxed.
+    #[derive(Clone, Default)]
+    struct SharedBuffer(std::rc::Rc<core::cell::RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn process_writes_audit_records_for_changes() {
+        let buffer = SharedBuffer::default();
+        let mut exchange = Exchange::new();
+        exchange.set_audit_writer(Box::new(buffer.clone()));
+
+        exchange
+            .process(Transaction::Deposit(
+                1,
+                5,
+                DEFAULT_ASSET.into(),
+                money("2.0"),
+            ))
+            .unwrap();
+        exchange
+            .process(Transaction::Withdrawal(
+                1,
+                6,
+                DEFAULT_ASSET.into(),
+                money("3.0"),
+            ))
+            .unwrap_err();
+        exchange.process(Transaction::Dispute(1, 5)).unwrap();
+        exchange.process(Transaction::Chargeback(1, 5)).unwrap();
+        exchange
+            .process(Transaction::Deposit(
+                2,
+                7,
+                DEFAULT_ASSET.into(),
+                money("0.3") + money("0.6"),
+            ))
+            .unwrap();
+
+        let audit = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        assert_eq!(
+            audit,
+            "deposit client=1 tx=5 amount=2.0000 available=2.0000\n\
+             dispute client=1 tx=5 held=2.0000\n\
+             chargeback client=1 tx=5 locked=true\n\
+             deposit client=2 tx=7 amount=0.9000 available=0.9000\n"
+        );
+    }
+
+    #[test]
+    fn process_sends_events_for_balance_changes() {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        let mut exchange = Exchange::new();
+        exchange.set_event_sender(sender);
+
+        exchange
+            .process(Transaction::Deposit(1, 5, "BTC".into(), money("2.0")))
+            .unwrap();
+        exchange
+            .process(Transaction::Withdrawal(
+                2,
+                6,
+                DEFAULT_ASSET.into(),
+                money("1.0"),
+            ))
+            .unwrap_err();
+        exchange.process(Transaction::Dispute(1, 5)).unwrap();
+
+        let events = receiver.try_iter().collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            vec![
+                ClientEvent {
+                    client: 1,
+                    asset: String::from("BTC"),
+                    balances: Balances {
+                        funds_available: money("2.0"),
+                        funds_held: money("0.0"),
+                    },
+                },
+                ClientEvent {
+                    client: 1,
+                    asset: String::from("BTC"),
+                    balances: Balances {
+                        funds_available: money("0.0"),
+                        funds_held: money("2.0"),
+                    },
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn freeze_locks_account_and_rejects_deposits() {
+        let mut exchange = ExchangeBuilder::new().enforce_locks(true).build();
+
+        exchange.deposit(5, 1, DEFAULT_ASSET, money("2.0")).unwrap();
+        assert_eq!(
+            exchange.process(Transaction::Freeze(1, 6)),
+            Ok(ProcessOutcome::Frozen)
+        );
+        assert!(exchange.is_locked(1));
+
+        assert_eq!(
+            exchange.process(Transaction::Deposit(
+                1,
+                7,
+                DEFAULT_ASSET.into(),
+                money("1.0")
+            )),
+            Err(AccountLocked)
+        );
+        assert_eq!(
+            exchange.process(Transaction::Withdrawal(
+                1,
+                8,
+                DEFAULT_ASSET.into(),
+                money("1.0")
+            )),
+            Err(AccountLocked)
+        );
+        assert_eq!(
+            exchange.clients[&1].funds(),
+            (money("2.0"), money("0.0"), money("2.0"))
+        );
+
+        assert_eq!(
+            exchange.process(Transaction::Unfreeze(1, 9)),
+            Ok(ProcessOutcome::Unfrozen)
+        );
+        assert!(!exchange.is_locked(1));
+        assert!(exchange
+            .process(Transaction::Deposit(
+                1,
+                7,
+                DEFAULT_ASSET.into(),
+                money("1.0")
+            ))
+            .is_ok());
+    }
+
+    #[test]
+    fn transfer_fails_to_frozen_account_with_enforced_locks() {
+        let mut exchange = ExchangeBuilder::new().enforce_locks(true).build();
+
+        exchange.deposit(5, 1, DEFAULT_ASSET, money("2.0")).unwrap();
+        exchange.deposit(6, 2, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.process(Transaction::Freeze(2, 7)).unwrap();
+        assert_eq!(
+            exchange.process(Transaction::Transfer(
+                1,
+                2,
+                8,
+                DEFAULT_ASSET.into(),
+                money("1.0")
+            )),
+            Err(AccountLocked)
+        );
+
+        assert_eq!(
+            exchange.clients[&1].funds(),
+            (money("2.0"), money("0.0"), money("2.0"))
+        );
+        assert_eq!(
+            exchange.clients[&2].funds(),
+            (money("1.0"), money("0.0"), money("1.0"))
+        );
+    }
+
+    #[test]
+    fn freeze_fails_if_client_doesnt_exist() {
+        let mut exchange = Exchange::new();
+
+        assert_eq!(
+            exchange.process(Transaction::Freeze(1, 1)),
+            Err(AccountNotFound)
+        );
+        assert_eq!(exchange.clients.len(), 0);
+    }
+
+    #[test]
+    fn locked_accounts_take_deposits_unless_locks_are_enforced() {
+        let mut exchange = Exchange::new();
 
-    /// The transaction has been disputed. The funds are held until the dispute
-    /// is resolved.
-    Disputed(f32),
+        exchange.deposit(5, 1, DEFAULT_ASSET, money("2.0")).unwrap();
+        exchange.process(Transaction::Freeze(1, 6)).unwrap();
 
-    /// The transaction had a dispute that has been resolved, either by a
-    /// Resolve or Chargeback transaction.
-    Resolved,
-}
+        assert!(exchange.deposit(7, 1, DEFAULT_ASSET, money("1.0")).is_ok());
+        assert!(exchange.is_locked(1));
+    }
 
-use TransactionState::*;
+    #[test]
+    fn unlock_client_clears_locked_flag() {
+        let mut exchange = Exchange::new();
 
-/// The exchange handles all transactions.
-///
-/// It keeps track of clients and transaction history. It handles deposits,
-/// withdrawals, and the dispute resolution process. All actions are done via
-/// transactions.
-pub struct Exchange {
-    transactions: HashMap<TxId, TransactionState>,
-    clients: HashMap<ClientId, Client>,
-}
+        exchange.deposit(5, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.dispute(5, 1).unwrap();
+        exchange.chargeback(5, 1).unwrap();
+        assert!(exchange.unlock_client(1).is_ok());
 
-impl Exchange {
-    /// Creates an empty exchange.
-    pub fn new() -> Exchange {
-        Exchange {
-            transactions: HashMap::new(),
-            clients: HashMap::new(),
-        }
+        assert!(!exchange.clients[&1].locked);
     }
 
-    pub fn process(&mut self, transaction: Transaction) -> Result<(), ExchangeError> {
-        use Transaction::*;
+    #[test]
+    fn is_locked_reports_locked_clients_only() {
+        let mut exchange = Exchange::new();
 
-        match transaction {
-            Deposit(client, tx, amount) => self.deposit(tx, client, amount),
-            Withdrawal(client, tx, amount) => self.withdraw(tx, client, amount),
-            Dispute(client, tx) => self.dispute(tx, client),
-            Resolve(client, tx) => self.resolve(tx, client),
-            Chargeback(client, tx) => self.chargeback(tx, client),
-        }
-    }
+        exchange.deposit(5, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.deposit(6, 2, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.dispute(5, 1).unwrap();
+        exchange.chargeback(5, 1).unwrap();
 
-    pub fn clients(&self) -> impl Iterator<Item = (&ClientId, &Client)> {
-        self.clients.iter()
+        assert!(exchange.is_locked(1));
+        assert!(!exchange.is_locked(2));
+        assert!(!exchange.is_locked(3));
     }
 
-    fn deposit(&mut self, tx: TxId, client: ClientId, amount: f32) -> Result<(), ExchangeError> {
-        let client = self.clients.entry(client).or_default();
+    #[test]
+    fn adjust_credits_and_debits_available_funds_with_audit_record() {
+        let buffer = SharedBuffer::default();
+        let mut exchange = Exchange::new();
+        exchange.set_audit_writer(Box::new(buffer.clone()));
 
-        match self.transactions.entry(tx) {
-            Entry::Occupied(_) => return Err(TransactionAlreadyExists),
-            Entry::Vacant(entry) => entry.insert(Completed(amount)),
-        };
+        exchange.deposit(5, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.adjust(1, money("2.0"), "missed deposit").unwrap();
+        exchange
+            .adjust(1, money("-0.5"), "fee refund reversed")
+            .unwrap();
 
-        client.funds_available += amount;
+        let balances = exchange.clients[&1].balance(DEFAULT_ASSET).unwrap();
+        assert_eq!(balances.funds_available, money("2.5"));
+        assert_eq!(
+            exchange.adjustments(),
+            &[
+                Adjustment {
+                    client: 1,
+                    delta: money("2.0"),
+                    reason: String::from("missed deposit"),
+                },
+                Adjustment {
+                    client: 1,
+                    delta: money("-0.5"),
+                    reason: String::from("fee refund reversed"),
+                },
+            ]
+        );
+        assert_eq!(exchange.reconcile(), vec![]);
 
-        Ok(())
+        let audit = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        assert!(audit.ends_with(
+            "adjustment client=1 delta=2.0000 reason=\"missed deposit\"\n\
+             adjustment client=1 delta=-0.5000 reason=\"fee refund reversed\"\n"
+        ));
     }
 
-    fn withdraw(&mut self, tx: TxId, client: ClientId, amount: f32) -> Result<(), ExchangeError> {
-        let client = self.clients.entry(client).or_default();
+    #[test]
+    fn adjust_fails_for_locked_or_overdrawn_accounts() {
+        let mut exchange = Exchange::new();
 
-        if client.funds_available < amount {
-            return Err(InsufficientFunds);
-        }
+        exchange.deposit(5, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        assert_eq!(
+            exchange.adjust(1, money("-2.0"), "typo"),
+            Err(InsufficientFunds)
+        );
+        assert_eq!(
+            exchange.adjust(2, money("1.0"), "typo"),
+            Err(AccountNotFound)
+        );
 
-        match self.transactions.entry(tx) {
-            Entry::Occupied(_) => return Err(TransactionAlreadyExists),
-            Entry::Vacant(entry) => entry.insert(Completed(-amount)),
-        };
+        exchange.dispute(5, 1).unwrap();
+        exchange.chargeback(5, 1).unwrap();
+        assert_eq!(
+            exchange.adjust(1, money("1.0"), "goodwill"),
+            Err(AccountLocked)
+        );
+        assert!(exchange.adjustments().is_empty());
+    }
 
-        client.funds_available -= amount;
+    #[test]
+    fn unlock_client_fails_if_client_doesnt_exist() {
+        let mut exchange = Exchange::new();
 
-        Ok(())
+        assert_eq!(exchange.unlock_client(1), Err(AccountNotFound));
+        assert!(!exchange.clients.contains_key(&1));
     }
 
-    fn dispute(&mut self, tx: TxId, client: ClientId) -> Result<(), ExchangeError> {
-        let state = self.transactions.get_mut(&tx).ok_or(TransactionNotFound)?;
-        let client = self.clients.entry(client).or_default();
+    #[test]
+    fn transactions_in_order_returns_processing_order() {
+        let mut exchange = Exchange::new();
 
-        let amount = match state {
-            Completed(amount) => *amount,
-            _ => return Err(TransactionAlreadyDisputed),
-        };
+        exchange.deposit(9, 1, DEFAULT_ASSET, money("4.0")).unwrap();
+        exchange.deposit(3, 2, DEFAULT_ASSET, money("2.0")).unwrap();
+        exchange
+            .withdraw(7, 1, DEFAULT_ASSET, money("1.0"))
+            .unwrap();
+        exchange
+            .withdraw(8, 2, DEFAULT_ASSET, money("5.0"))
+            .unwrap_err();
+        exchange.deposit(1, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.dispute(3, 2).unwrap();
 
-        *state = Disputed(amount);
-        client.funds_available -= amount;
-        client.funds_held += amount;
+        let order = exchange.transactions_in_order().collect::<Vec<_>>();
+        assert_eq!(order, vec![9, 3, 7, 1]);
+    }
 
-        Ok(())
+    #[test]
+    fn transaction_states_reports_final_states_in_order() {
+        let mut exchange = Exchange::new();
+
+        exchange.deposit(4, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.deposit(3, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.deposit(2, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.deposit(1, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.dispute(3, 1).unwrap();
+        exchange.dispute(2, 1).unwrap();
+        exchange.resolve(2, 1).unwrap();
+        exchange.dispute(1, 1).unwrap();
+        exchange.chargeback(1, 1).unwrap();
+
+        let states = exchange.transaction_states().collect::<Vec<_>>();
+        assert_eq!(
+            states,
+            vec![
+                (4, TransactionStatus::Completed),
+                (3, TransactionStatus::Disputed),
+                (2, TransactionStatus::Resolved),
+                (1, TransactionStatus::ChargedBack),
+            ]
+        );
     }
 
-    fn resolve(&mut self, tx: TxId, client: ClientId) -> Result<(), ExchangeError> {
-        let state = self.transactions.get_mut(&tx).ok_or(TransactionNotFound)?;
-        let client = self.clients.entry(client).or_default();
+    #[test]
+    fn clients_with_holds_returns_only_clients_with_held_funds() {
+        let mut exchange = Exchange::new();
+
+        exchange.deposit(1, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.deposit(2, 2, "BTC", money("2.0")).unwrap();
+        exchange.deposit(3, 3, DEFAULT_ASSET, money("3.0")).unwrap();
+        exchange.deposit(4, 4, DEFAULT_ASSET, money("4.0")).unwrap();
+        exchange.dispute(2, 2).unwrap();
+        exchange.dispute(3, 3).unwrap();
+        exchange.resolve(3, 3).unwrap();
 
-        let amount = match state {
-            Disputed(amount) => *amount,
-            _ => return Err(TransactionNotDisputed),
-        };
+        let clients = exchange
+            .clients_with_holds()
+            .map(|(&id, _)| id)
+            .collect::<Vec<_>>();
+        assert_eq!(clients, vec![2]);
+    }
+
+    #[test]
+    fn clients_where_returns_only_matching_clients() {
+        let mut exchange = Exchange::new();
 
-        *state = Resolved;
-        client.funds_available += amount;
-        client.funds_held -= amount;
+        exchange.deposit(1, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.deposit(2, 2, DEFAULT_ASSET, money("5.0")).unwrap();
+        exchange.deposit(3, 3, DEFAULT_ASSET, money("2.5")).unwrap();
+        exchange
+            .deposit(4, 4, DEFAULT_ASSET, money("10.0"))
+            .unwrap();
+        exchange
+            .withdraw(5, 4, DEFAULT_ASSET, money("9.0"))
+            .unwrap();
 
-        Ok(())
+        let threshold = money("2.0");
+        let mut clients = exchange
+            .clients_where(|client| client.funds().0 > threshold)
+            .map(|(&id, _)| id)
+            .collect::<Vec<_>>();
+        clients.sort_unstable();
+        assert_eq!(clients, vec![2, 3]);
     }
 
-    fn chargeback(&mut self, tx: TxId, client: ClientId) -> Result<(), ExchangeError> {
-        let state = self.transactions.get_mut(&tx).ok_or(TransactionNotFound)?;
-        let client = self.clients.entry(client).or_default();
+    #[test]
+    fn clients_sorted_copies_clients_in_id_order() {
+        let mut exchange = Exchange::new();
+
+        exchange.deposit(1, 7, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.deposit(2, 2, DEFAULT_ASSET, money("2.0")).unwrap();
+        exchange.deposit(3, 4, "BTC", money("3.0")).unwrap();
 
-        let amount = match state {
-            Disputed(amount) => *amount,
-            _ => return Err(TransactionNotDisputed),
+        let client = |asset: &str, available| {
+            let mut client = Client::new();
+            client.balance_mut(asset).funds_available = available;
+            client
         };
+        assert_eq!(
+            exchange.clients_sorted(),
+            vec![
+                (2, client(DEFAULT_ASSET, money("2.0"))),
+                (4, client("BTC", money("3.0"))),
+                (7, client(DEFAULT_ASSET, money("1.0"))),
+            ]
+        );
+    }
 
-        *state = Resolved;
-        client.funds_held -= amount;
-        client.locked = true;
+    #[test]
+    fn clients_returns_all_clients() {
+        let mut exchange = Exchange::new();
 
-        Ok(())
-    }
-}
+        exchange.deposit(0, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.deposit(1, 2, DEFAULT_ASSET, money("2.0")).unwrap();
+        exchange.deposit(2, 5, DEFAULT_ASSET, money("4.0")).unwrap();
+        exchange
+            .withdraw(3, 2, DEFAULT_ASSET, money("1.0"))
+            .unwrap();
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+        let clients = exchange.clients().collect::<Vec<_>>();
+        assert_eq!(
+            clients
+                .iter()
+                .find(|(&k, _)| k == 1)
+                .and_then(|(_, v)| v.balance(DEFAULT_ASSET)),
+            Some(&Balances {
+                funds_available: money("1.0"),
+                funds_held: money("0.0"),
+            })
+        );
+        assert_eq!(
+            clients
+                .iter()
+                .find(|(&k, _)| k == 2)
+                .and_then(|(_, v)| v.balance(DEFAULT_ASSET)),
+            Some(&Balances {
+                funds_available: money("1.0"),
+                funds_held: money("0.0"),
+            })
+        );
+        assert_eq!(
+            clients
+                .iter()
+                .find(|(&k, _)| k == 5)
+                .and_then(|(_, v)| v.balance(DEFAULT_ASSET)),
+            Some(&Balances {
+                funds_available: money("4.0"),
+                funds_held: money("0.0"),
+            })
+        );
+    }
 
     #[test]
-    fn deposit_succeeds_and_adds_funds_with_unique_tx_id() {
+    fn state_counts_tallies_transactions_by_state() {
         let mut exchange = Exchange::new();
 
-        assert!(exchange.deposit(5, 1, 1.0).is_ok());
+        for tx in 1..=6 {
+            exchange
+                .deposit(tx, 1, DEFAULT_ASSET, money("1.0"))
+                .unwrap();
+        }
+        exchange
+            .withdraw(7, 1, DEFAULT_ASSET, money("1.0"))
+            .unwrap();
+        exchange.dispute(1, 1).unwrap();
+        exchange.dispute(2, 1).unwrap();
+        exchange.dispute(3, 1).unwrap();
+        exchange.resolve(2, 1).unwrap();
+        exchange.chargeback(3, 1).unwrap();
 
-        let client = exchange.clients.get(&1).unwrap();
-        assert_eq!(client.funds_held, 0.0);
-        assert_eq!(client.funds_available, 1.0);
+        assert_eq!(
+            exchange.state_counts(),
+            StateCounts {
+                completed: 4,
+                disputed: 1,
+                resolved: 1,
+                charged_back: 1,
+            }
+        );
     }
 
     #[test]
-    fn deposit_fails_with_non_unique_tx_id() {
+    fn total_disputes_counts_every_dispute_filed() {
         let mut exchange = Exchange::new();
 
-        exchange.deposit(5, 1, 1.0).unwrap();
-        assert_eq!(exchange.deposit(5, 1, 2.0), Err(TransactionAlreadyExists));
+        for tx in 1..=3 {
+            exchange
+                .deposit(tx, 1, DEFAULT_ASSET, money("1.0"))
+                .unwrap();
+        }
+        exchange.dispute(1, 1).unwrap();
+        exchange.dispute(2, 1).unwrap();
+        exchange.dispute(3, 1).unwrap();
+        exchange.resolve(1, 1).unwrap();
+        exchange.chargeback(2, 1).unwrap();
+        assert_eq!(exchange.dispute(3, 1), Err(TransactionAlreadyDisputed));
 
-        exchange.withdraw(6, 1, 1.0).unwrap();
-        assert_eq!(exchange.deposit(6, 1, 2.0), Err(TransactionAlreadyExists));
+        assert_eq!(exchange.total_disputes(), 3);
     }
 
     #[test]
-    fn withdraw_succeeds_and_pulls_funds_with_unique_tx_id() {
+    fn fold_clients_computes_aggregates() {
         let mut exchange = Exchange::new();
 
-        exchange.deposit(5, 1, 1.0).unwrap();
-        assert!(exchange.withdraw(6, 1, 1.0).is_ok());
+        exchange.deposit(1, 1, DEFAULT_ASSET, money("1.5")).unwrap();
+        exchange.deposit(2, 2, DEFAULT_ASSET, money("2.0")).unwrap();
+        exchange.deposit(3, 3, DEFAULT_ASSET, money("0.5")).unwrap();
+        exchange
+            .withdraw(4, 2, DEFAULT_ASSET, money("1.0"))
+            .unwrap();
 
-        let client = exchange.clients.get(&1).unwrap();
-        assert_eq!(client.funds_held, 0.0);
-        assert_eq!(client.funds_available, 0.0);
+        let total = exchange.fold_clients(money("0.0"), |total, _, client| {
+            total + client.balance(DEFAULT_ASSET).unwrap().funds_available
+        });
+        assert_eq!(total, money("3.0"));
     }
 
     #[test]
-    fn withdraw_fails_with_non_unique_id() {
-        let mut exchange = Exchange::new();
+    fn new_ordered_iterates_over_clients_in_id_order() {
+        let mut exchange = Exchange::new_ordered();
+
+        for (tx, client) in [(1, 40), (2, 3), (3, 1000), (4, 7), (5, 1)].iter() {
+            exchange
+                .deposit(*tx, *client, DEFAULT_ASSET, money("1.0"))
+                .unwrap();
+        }
 
-        exchange.deposit(5, 1, 4.0).unwrap();
-        assert_eq!(exchange.withdraw(5, 1, 1.0), Err(TransactionAlreadyExists));
+        let ids = exchange.clients().map(|(&id, _)| id).collect::<Vec<_>>();
+        assert_eq!(ids, vec![1, 3, 7, 40, 1000]);
 
-        exchange.withdraw(6, 1, 2.0).unwrap();
-        assert_eq!(exchange.withdraw(6, 1, 1.0), Err(TransactionAlreadyExists));
+        exchange.clear();
+        exchange.deposit(6, 9, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.deposit(7, 2, DEFAULT_ASSET, money("1.0")).unwrap();
+        let ids = exchange.clients().map(|(&id, _)| id).collect::<Vec<_>>();
+        assert_eq!(ids, vec![2, 9]);
     }
 
     #[test]
-    fn withdraw_fails_if_client_has_insufficient_funds() {
+    fn with_capacity_behaves_like_new() {
+        let mut presized = Exchange::with_capacity(4, 16);
         let mut exchange = Exchange::new();
 
-        exchange.deposit(5, 1, 1.0).unwrap();
-        assert_eq!(exchange.withdraw(6, 1, 2.0), Err(InsufficientFunds));
+        for exchange in [&mut presized, &mut exchange].iter_mut() {
+            exchange.deposit(1, 1, DEFAULT_ASSET, money("2.0")).unwrap();
+            exchange.deposit(2, 2, "BTC", money("1.0")).unwrap();
+            exchange
+                .withdraw(3, 1, DEFAULT_ASSET, money("0.5"))
+                .unwrap();
+            exchange.dispute(2, 2).unwrap();
+        }
 
-        let client = exchange.clients.get(&1).unwrap();
-        assert_eq!(client.funds_held, 0.0);
-        assert_eq!(client.funds_available, 1.0);
+        assert_eq!(presized.clients_sorted(), exchange.clients_sorted());
+        assert_eq!(
+            presized.transactions_in_order().collect::<Vec<_>>(),
+            exchange.transactions_in_order().collect::<Vec<_>>()
+        );
     }
 
     #[test]
-    fn dispute_succeeds_and_holds_funds_on_existing_transaction() {
-        let mut exchange = Exchange::new();
+    fn with_capacity_doesnt_grow_within_capacity() {
+        let mut exchange = Exchange::with_capacity(100, 1000);
+        let clients = exchange.clients.capacity();
+        let transactions = exchange.transactions.capacity();
+        let order = exchange.transaction_order.capacity();
 
-        exchange.deposit(5, 1, 1.0).unwrap();
-        assert!(exchange.dispute(5, 1).is_ok());
+        for tx in 0..1000 {
+            exchange
+                .deposit(tx, (tx % 100) as ClientId, DEFAULT_ASSET, money("1.0"))
+                .unwrap();
+        }
 
-        let client = exchange.clients.get(&1).unwrap();
-        assert_eq!(client.funds_held, 1.0);
-        assert_eq!(client.funds_available, 0.0);
+        // no map or list had to reallocate to grow
+        assert_eq!(exchange.clients.capacity(), clients);
+        assert_eq!(exchange.transactions.capacity(), transactions);
+        assert_eq!(exchange.transaction_order.capacity(), order);
     }
 
     #[test]
-    fn dispute_fails_if_transaction_doesnt_exist() {
+    fn is_disputable_only_for_completed_transactions() {
         let mut exchange = Exchange::new();
 
-        assert_eq!(exchange.dispute(5, 1), Err(TransactionNotFound));
+        for tx in 1..=4 {
+            exchange
+                .deposit(tx, 1, DEFAULT_ASSET, money("1.0"))
+                .unwrap();
+        }
+        exchange.dispute(2, 1).unwrap();
+        exchange.dispute(3, 1).unwrap();
+        exchange.resolve(3, 1).unwrap();
+        exchange.dispute(4, 1).unwrap();
+        exchange.chargeback(4, 1).unwrap();
+
+        assert!(exchange.is_disputable(1));
+        assert!(!exchange.is_disputable(2));
+        assert!(!exchange.is_disputable(3));
+        assert!(!exchange.is_disputable(4));
+        assert!(!exchange.is_disputable(5));
     }
 
     #[test]
-    fn dispute_fails_if_transaction_is_already_disputed() {
+    fn contains_transaction_whatever_its_state() {
         let mut exchange = Exchange::new();
 
-        exchange.deposit(5, 1, 1.0).unwrap();
-        exchange.dispute(5, 1).unwrap();
-        assert_eq!(exchange.dispute(5, 1), Err(TransactionAlreadyDisputed));
+        exchange.deposit(1, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.deposit(2, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.dispute(2, 1).unwrap();
+        exchange.resolve(2, 1).unwrap();
+        exchange
+            .withdraw(3, 1, DEFAULT_ASSET, money("5.0"))
+            .unwrap_err();
+
+        assert!(exchange.contains_transaction(1));
+        assert!(exchange.contains_transaction(2));
+        assert!(!exchange.contains_transaction(3));
     }
 
     #[test]
-    fn dispute_fails_if_transaction_is_already_resolved() {
-        let mut exchange = Exchange::new();
+    fn is_disputable_follows_session_disputes() {
+        let mut exchange = ExchangeBuilder::new().session_disputes(true).build();
 
-        exchange.deposit(5, 1, 1.0).unwrap();
-        exchange.dispute(5, 1).unwrap();
-        exchange.resolve(5, 1).unwrap();
-        assert_eq!(exchange.dispute(5, 1), Err(TransactionAlreadyDisputed));
+        exchange.deposit(1, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange
+            .process(Transaction::SessionBoundary(1, 2))
+            .unwrap();
+        exchange.deposit(3, 1, DEFAULT_ASSET, money("1.0")).unwrap();
 
-        exchange.deposit(6, 1, 1.0).unwrap();
-        exchange.dispute(6, 1).unwrap();
-        exchange.chargeback(6, 1).unwrap();
-        assert_eq!(exchange.dispute(6, 1), Err(TransactionAlreadyDisputed));
+        assert!(!exchange.is_disputable(1));
+        assert!(exchange.is_disputable(3));
     }
 
     #[test]
-    fn resolve_succeeds_and_releases_held_funds_on_disputed_transaction() {
+    fn flows_total_deposits_and_withdrawals_regardless_of_disputes() {
+        let mut exchange = ExchangeBuilder::new().partial_withdrawals(true).build();
+
+        exchange.deposit(1, 1, DEFAULT_ASSET, money("5.0")).unwrap();
+        exchange.deposit(2, 1, DEFAULT_ASSET, money("2.5")).unwrap();
+        exchange
+            .withdraw(3, 1, DEFAULT_ASSET, money("1.5"))
+            .unwrap();
+        exchange.deposit(4, 1, "BTC", money("1.0")).unwrap();
+        exchange.dispute(2, 1).unwrap();
+        exchange.chargeback(2, 1).unwrap();
+        // only what's available is withdrawn
+        exchange
+            .withdraw(5, 1, DEFAULT_ASSET, money("10.0"))
+            .unwrap();
+
+        let flows = exchange.flows(1, DEFAULT_ASSET);
+        assert_eq!(flows.deposited, money("7.5"));
+        assert_eq!(flows.withdrawn, money("5.0"));
+        assert_eq!(flows.net(), money("2.5"));
+        assert_eq!(exchange.flows(1, "BTC").net(), money("1.0"));
+        assert_eq!(exchange.flows(2, DEFAULT_ASSET), Flows::new());
+    }
+
+    #[test]
+    fn held_transactions_lists_clients_disputed_transactions() {
         let mut exchange = Exchange::new();
 
-        exchange.deposit(5, 1, 1.0).unwrap();
+        exchange.deposit(5, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.deposit(6, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.deposit(7, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.deposit(8, 2, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.dispute(7, 1).unwrap();
         exchange.dispute(5, 1).unwrap();
-        assert!(exchange.resolve(5, 1).is_ok());
+        exchange.dispute(8, 2).unwrap();
 
-        let client = exchange.clients.get(&1).unwrap();
-        assert_eq!(client.funds_held, 0.0);
-        assert_eq!(client.funds_available, 1.0);
+        assert_eq!(exchange.held_transactions(1), vec![5, 7]);
+        assert_eq!(exchange.held_transactions(3), vec![]);
     }
 
     #[test]
-    fn resolve_fails_if_transaction_doesnt_exists() {
+    fn client_transactions_lists_all_of_clients_transactions() {
         let mut exchange = Exchange::new();
 
-        assert_eq!(exchange.resolve(5, 1), Err(TransactionNotFound));
+        exchange.deposit(1, 1, DEFAULT_ASSET, money("2.0")).unwrap();
+        exchange.deposit(2, 2, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.dispute(1, 1).unwrap();
+        exchange.resolve(1, 1).unwrap();
+        exchange
+            .withdraw(3, 1, DEFAULT_ASSET, money("1.0"))
+            .unwrap();
+
+        assert_eq!(exchange.client_transactions(1), vec![1, 3]);
+        assert_eq!(exchange.client_transactions(3), vec![]);
     }
 
     #[test]
-    fn resolve_fails_if_transaction_is_not_disputed() {
-        let mut exchange = Exchange::new();
+    fn expire_disputes_resolves_disputes_older_than_window() {
+        let mut exchange = ExchangeBuilder::new().dispute_window(60).build();
 
-        exchange.deposit(5, 1, 1.0).unwrap();
-        assert_eq!(exchange.resolve(5, 1), Err(TransactionNotDisputed));
+        exchange.deposit(1, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.deposit(2, 1, DEFAULT_ASSET, money("2.0")).unwrap();
+        exchange.expire_disputes(1_000);
+        exchange.dispute(1, 1).unwrap();
+        exchange.expire_disputes(1_030);
+        exchange.dispute(2, 1).unwrap();
+
+        assert_eq!(exchange.expire_disputes(1_059), vec![]);
+        assert_eq!(exchange.expire_disputes(1_060), vec![1]);
+
+        let client = &exchange.clients[&1];
+        let balances = client.balance(DEFAULT_ASSET).unwrap();
+        assert_eq!(balances.funds_available, money("1.0"));
+        assert_eq!(balances.funds_held, money("2.0"));
+        assert_eq!(exchange.held_transactions(1), vec![2]);
     }
 
     #[test]
-    fn resolve_fails_if_transaction_already_resolved() {
-        let mut exchange = Exchange::new();
+    fn expiring_the_last_dispute_leaves_exactly_nothing_held() {
+        let mut exchange = ExchangeBuilder::new().dispute_window(60).build();
 
-        exchange.deposit(5, 1, 1.0).unwrap();
-        exchange.dispute(5, 1).unwrap();
-        exchange.resolve(5, 1).unwrap();
-        assert_eq!(exchange.resolve(5, 1), Err(TransactionNotDisputed));
+        exchange.deposit(1, 1, DEFAULT_ASSET, money("0.1")).unwrap();
+        exchange.deposit(2, 1, DEFAULT_ASSET, money("0.2")).unwrap();
+        exchange.expire_disputes(1_000);
+        exchange.dispute(1, 1).unwrap();
+        exchange.dispute(2, 1).unwrap();
+        exchange.resolve(1, 1).unwrap();
 
-        exchange.deposit(6, 1, 1.0).unwrap();
-        exchange.dispute(6, 1).unwrap();
-        exchange.chargeback(6, 1).unwrap();
-        assert_eq!(exchange.resolve(6, 1), Err(TransactionNotDisputed));
+        assert_eq!(exchange.expire_disputes(1_060), vec![2]);
+        let balances = exchange.clients[&1].balance(DEFAULT_ASSET).unwrap();
+        assert_eq!(balances.funds_held, money("0.0"));
     }
 
     #[test]
-    fn chargeback_succeeds_and_removes_held_funds_and_locks_client_on_disputed_transaction() {
+    fn process_at_keeps_the_transaction_timestamp() {
+        let mut exchange = ExchangeBuilder::new().idempotent(true).build();
+
+        let deposit = || Transaction::Deposit(1, 1, DEFAULT_ASSET.into(), money("1.0"));
+        exchange.process_at(deposit(), 100).unwrap();
+        exchange
+            .process(Transaction::Deposit(
+                1,
+                2,
+                DEFAULT_ASSET.into(),
+                money("1.0"),
+            ))
+            .unwrap();
+        // resubmitting it later doesn't change when it happened
+        exchange.process_at(deposit(), 200).unwrap();
+
+        assert_eq!(exchange.transaction_timestamp(1), Some(100));
+        assert_eq!(exchange.transaction_timestamp(2), None);
+        assert_eq!(exchange.transaction_timestamp(3), None);
+    }
+
+    #[test]
+    fn process_at_dates_disputes_by_their_timestamp() {
+        let mut exchange = ExchangeBuilder::new().dispute_window(60).build();
+
+        exchange
+            .process_at(
+                Transaction::Deposit(1, 1, DEFAULT_ASSET.into(), money("1.0")),
+                900,
+            )
+            .unwrap();
+        exchange
+            .process_at(Transaction::Dispute(1, 1), 1_000)
+            .unwrap();
+
+        assert_eq!(exchange.expire_disputes(1_059), vec![]);
+        assert_eq!(exchange.expire_disputes(1_060), vec![1]);
+    }
+
+    #[test]
+    fn expire_disputes_handles_extreme_times() {
+        let mut exchange = ExchangeBuilder::new().dispute_window(60).build();
+
+        exchange.deposit(1, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.expire_disputes(i64::MAX);
+        exchange.dispute(1, 1).unwrap();
+
+        assert_eq!(exchange.expire_disputes(i64::MIN), vec![]);
+        assert_eq!(exchange.held_transactions(1), vec![1]);
+    }
+
+    #[test]
+    fn clear_empties_exchange_for_reuse() {
         let mut exchange = Exchange::new();
 
-        exchange.deposit(5, 1, 1.0).unwrap();
+        exchange.deposit(1, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.adjust(1, money("1.0"), "bonus").unwrap();
+        exchange.clear();
+
+        assert_eq!(exchange.clients().count(), 0);
+        assert_eq!(exchange.transactions_in_order().count(), 0);
+        assert!(exchange.adjustments().is_empty());
+        assert_eq!(exchange.client_transactions(1), vec![]);
+
+        exchange.deposit(2, 1, DEFAULT_ASSET, money("2.0")).unwrap();
+        exchange.deposit(1, 1, DEFAULT_ASSET, money("3.0")).unwrap();
+        let balances = exchange.clients[&1].balance(DEFAULT_ASSET).unwrap();
+        assert_eq!(balances.funds_available, money("5.0"));
+    }
+
+    #[test]
+    fn reconcile_passes_within_epsilon() {
+        let mut exchange = Exchange::<f32>::default();
+
+        // disputing the first deposit after the second leaves 0.1 + 0.2 - 0.1,
+        // which isn't quite the 0.2 replaying gives
+        exchange.deposit(5, 1, DEFAULT_ASSET, 0.1).unwrap();
+        exchange.deposit(6, 1, DEFAULT_ASSET, 0.2).unwrap();
         exchange.dispute(5, 1).unwrap();
-        assert!(exchange.chargeback(5, 1).is_ok());
+        exchange.deposit(7, 2, DEFAULT_ASSET, 1.0).unwrap();
 
-        let client = exchange.clients.get(&1).unwrap();
-        assert_eq!(client.funds_held, 0.0);
-        assert_eq!(client.funds_available, 0.0);
-        assert_eq!(client.locked, true);
+        let available = exchange.clients[&1]
+            .balance(DEFAULT_ASSET)
+            .unwrap()
+            .funds_available;
+        assert_ne!(available, 0.2);
+        assert_eq!(exchange.reconcile(), vec![]);
+
+        let mut exact = ExchangeBuilder::<f32>::default().epsilon(0.0).build();
+        exact.deposit(5, 1, DEFAULT_ASSET, 0.1).unwrap();
+        exact.deposit(6, 1, DEFAULT_ASSET, 0.2).unwrap();
+        exact.dispute(5, 1).unwrap();
+        assert_eq!(exact.reconcile(), vec![1]);
     }
 
     #[test]
-    fn chargeback_fails_if_transaction_doesnt_exists() {
+    fn reconcile_reports_clients_with_mismatched_balances() {
         let mut exchange = Exchange::new();
 
-        assert_eq!(exchange.chargeback(5, 1), Err(TransactionNotFound));
+        exchange.deposit(5, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.deposit(6, 2, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange
+            .clients
+            .get_mut(&2)
+            .unwrap()
+            .balance_mut("BTC")
+            .funds_held = money("1.0");
+
+        assert_eq!(exchange.reconcile(), vec![2]);
     }
 
     #[test]
-    fn chargeback_fails_if_transaction_is_not_disputed() {
-        let mut exchange = Exchange::new();
+    fn diff_lists_only_clients_that_differ() {
+        let mut live = Exchange::new();
+        let mut other = Exchange::new();
 
-        exchange.deposit(5, 1, 1.0).unwrap();
-        assert_eq!(exchange.chargeback(5, 1), Err(TransactionNotDisputed));
+        for exchange in [&mut live, &mut other].iter_mut() {
+            exchange.deposit(1, 1, DEFAULT_ASSET, money("2.0")).unwrap();
+            exchange.deposit(2, 2, DEFAULT_ASSET, money("3.0")).unwrap();
+            exchange.deposit(3, 3, DEFAULT_ASSET, money("1.0")).unwrap();
+        }
+        other.dispute(2, 2).unwrap();
+        other.chargeback(2, 2).unwrap();
+        other.deposit(4, 2, DEFAULT_ASSET, money("0.5")).unwrap();
+
+        let mut delta = Map::new();
+        delta.insert(
+            DEFAULT_ASSET.to_owned(),
+            Balances {
+                funds_available: money("-2.5"),
+                funds_held: money("0.0"),
+            },
+        );
+        assert_eq!(
+            live.diff(&other),
+            vec![ClientDiff {
+                client: 2,
+                balances: delta,
+                locked: Some(true),
+            }]
+        );
+        assert_eq!(live.diff(&live), vec![]);
     }
 
     #[test]
-    fn chargeback_fails_if_transaction_already_resolved() {
+    fn recompute_balances_matches_processed_balances() {
         let mut exchange = Exchange::new();
 
-        exchange.deposit(5, 1, 1.0).unwrap();
-        exchange.dispute(5, 1).unwrap();
-        exchange.resolve(5, 1).unwrap();
-        assert_eq!(exchange.chargeback(5, 1), Err(TransactionNotDisputed));
+        exchange
+            .deposit(1, 1, DEFAULT_ASSET, money("10.0"))
+            .unwrap();
+        exchange.deposit(2, 1, "BTC", money("4.0")).unwrap();
+        exchange.deposit(3, 2, DEFAULT_ASSET, money("8.0")).unwrap();
+        exchange
+            .withdraw(4, 1, DEFAULT_ASSET, money("2.5"))
+            .unwrap();
+        exchange
+            .withdraw(5, 2, DEFAULT_ASSET, money("3.0"))
+            .unwrap();
+        exchange.deposit(6, 2, DEFAULT_ASSET, money("1.5")).unwrap();
+        exchange.dispute(2, 1).unwrap();
+        exchange.dispute(4, 1).unwrap();
+        exchange.resolve(4, 1).unwrap();
+        exchange.dispute(5, 2).unwrap();
+        exchange.chargeback(5, 2).unwrap();
+        exchange.dispute(6, 2).unwrap();
+        exchange.chargeback(6, 2).unwrap();
 
-        exchange.deposit(6, 1, 1.0).unwrap();
-        exchange.dispute(6, 1).unwrap();
-        exchange.chargeback(6, 1).unwrap();
-        assert_eq!(exchange.chargeback(6, 1), Err(TransactionNotDisputed));
+        let snapshot = |exchange: &Exchange| {
+            let mut balances = exchange
+                .clients()
+                .flat_map(|(id, client)| {
+                    client.balances.iter().map(move |(asset, balances)| {
+                        let funds = (balances.funds_available, balances.funds_held);
+                        (*id, asset.clone(), funds, client.locked)
+                    })
+                })
+                .collect::<Vec<_>>();
+            balances.sort_by(|a, b| (a.0, &a.1).cmp(&(b.0, &b.1)));
+            balances
+        };
+
+        let before = snapshot(&exchange);
+        exchange.recompute_balances();
+
+        assert_eq!(snapshot(&exchange), before);
     }
 
     #[test]
-    fn clients_returns_all_clients() {
-        let mut exchange = Exchange::new();
+    fn deposit_succeeds_with_default_money_type() {
+        let mut exchange = Exchange::<f32>::default();
 
-        exchange.deposit(0, 1, 1.0).unwrap();
-        exchange.deposit(1, 2, 2.0).unwrap();
-        exchange.deposit(2, 5, 4.0).unwrap();
-        exchange.withdraw(3, 2, 1.0).unwrap();
+        let outcome = exchange.process(Transaction::Deposit(1, 5, DEFAULT_ASSET.into(), 1.5));
 
-        let clients = exchange.clients().collect::<Vec<_>>();
         assert_eq!(
-            clients.iter().find(|(&k, _)| k == 1).map(|(_, v)| *v),
-            Some(&Client {
-                funds_available: 1.0,
-                funds_held: 0.0,
-                locked: false,
-            })
+            outcome,
+            Ok(ProcessOutcome::Deposited { new_available: 1.5 })
         );
+    }
+
+    #[test]
+    fn deposit_succeeds_with_another_money_type() {
+        let mut exchange = ExchangeBuilder::<f64>::default().build();
+
+        exchange
+            .process(Transaction::Deposit(1, 5, DEFAULT_ASSET.into(), 0.1))
+            .unwrap();
+        exchange
+            .process(Transaction::Deposit(1, 6, DEFAULT_ASSET.into(), 0.2))
+            .unwrap();
+
+        let balances = exchange.clients[&1].balance(DEFAULT_ASSET).unwrap();
+        assert_eq!(balances.funds_available, 0.1f64 + 0.2f64);
+    }
+
+    #[test]
+    fn per_client_tx_ids_let_clients_dispute_their_own_transaction() {
+        let mut exchange = ExchangeBuilder::new().per_client_tx_ids(true).build();
+
+        exchange.deposit(5, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.deposit(5, 2, DEFAULT_ASSET, money("2.0")).unwrap();
+        exchange.dispute(5, 1).unwrap();
+        exchange.dispute(5, 2).unwrap();
+        exchange.chargeback(5, 2).unwrap();
+
+        let client = &exchange.clients[&1];
+        let balances = client.balance(DEFAULT_ASSET).unwrap();
+        assert_eq!(balances.funds_held, money("1.0"));
+        assert_eq!(balances.funds_available, money("0.0"));
+        assert!(!client.locked);
+
+        let client = &exchange.clients[&2];
+        let balances = client.balance(DEFAULT_ASSET).unwrap();
+        assert_eq!(balances.funds_held, money("0.0"));
+        assert_eq!(balances.funds_available, money("0.0"));
+        assert!(client.locked);
+    }
+
+    #[test]
+    fn per_client_tx_ids_dont_find_other_clients_transactions() {
+        let mut exchange = ExchangeBuilder::new().per_client_tx_ids(true).build();
+
+        exchange.deposit(5, 1, DEFAULT_ASSET, money("1.0")).unwrap();
+        exchange.deposit(6, 2, DEFAULT_ASSET, money("1.0")).unwrap();
+
+        assert_eq!(exchange.dispute(5, 2), Err(TransactionNotFound));
+    }
+}
+
+#[cfg(test)]
+mod fuzz_tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[cfg(not(feature = "decimal"))]
+    fn amount() -> impl Strategy<Value = Money> {
+        any::<f32>()
+    }
+
+    #[cfg(feature = "decimal")]
+    fn amount() -> impl Strategy<Value = Money> {
+        // any 96 bit mantissa with any scale, so up to the largest decimal
+        (any::<(u32, u32, u32, bool)>(), 0..=28u32).prop_map(|((lo, mid, hi, negative), scale)| {
+            Money::from_parts(lo, mid, hi, negative, scale)
+        })
+    }
+
+    fn transaction() -> impl Strategy<Value = Transaction> {
+        // small id ranges so that transactions frequently refer to each other
+        let client = 0..4u16;
+        let tx = 0..32u32;
+        let asset = prop_oneof![Just(""), Just("BTC")];
+
+        prop_oneof![
+            (client.clone(), tx.clone(), asset.clone(), amount())
+                .prop_map(|(c, t, a, m)| Transaction::Deposit(c, t, a.to_owned(), m)),
+            (client.clone(), tx.clone(), asset.clone(), amount())
+                .prop_map(|(c, t, a, m)| Transaction::Withdrawal(c, t, a.to_owned(), m)),
+            (client.clone(), client.clone(), tx.clone(), asset, amount())
+                .prop_map(|(f, c, t, a, m)| Transaction::Transfer(f, c, t, a.to_owned(), m)),
+            (client.clone(), tx.clone()).prop_map(|(c, t)| Transaction::Dispute(c, t)),
+            (client.clone(), tx.clone()).prop_map(|(c, t)| Transaction::Resolve(c, t)),
+            (client.clone(), tx.clone()).prop_map(|(c, t)| Transaction::Chargeback(c, t)),
+            (client.clone(), tx.clone()).prop_map(|(c, t)| Transaction::SessionBoundary(c, t)),
+            (client.clone(), tx.clone()).prop_map(|(c, t)| Transaction::Freeze(c, t)),
+            (client, tx).prop_map(|(c, t)| Transaction::Unfreeze(c, t)),
+        ]
+    }
+
+    fn fee() -> impl Strategy<Value = Fee> {
+        // invalid fees too, which the builder should ignore
+        prop_oneof![amount().prop_map(Fee::Flat), amount().prop_map(Fee::Rate)]
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(5000))]
+
+        #[test]
+        fn process_never_panics(
+            transactions in prop::collection::vec(transaction(), 0..64),
+            fee in fee(),
+        ) {
+            let mut exchange = Exchange::new();
+            // the policies with arithmetic of their own
+            let mut lenient = ExchangeBuilder::new()
+                .withdrawal_fee(fee)
+                .partial_withdrawals(true)
+                .allow_negative_available(true)
+                .build();
+
+            for transaction in transactions {
+                let _ = exchange.process(transaction.clone());
+                let _ = lenient.process(transaction);
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "bincode"))]
+mod bincode_tests {
+    use super::*;
+    use crate::money::money;
+
+    #[test]
+    fn restore_bin_restores_snapshot_bin() {
+        let mut exchange = ExchangeBuilder::new().max_clients(2).build();
+
+        exchange.deposit(1, 1, DEFAULT_ASSET, money("2.0")).unwrap();
+        exchange.deposit(2, 2, "BTC", money("1.5")).unwrap();
+        exchange
+            .withdraw(3, 1, DEFAULT_ASSET, money("0.5"))
+            .unwrap();
+        exchange.dispute(2, 2).unwrap();
+        exchange.adjust(1, money("0.25"), "fee refund").unwrap();
+        exchange
+            .process(Transaction::Transfer(
+                1,
+                2,
+                4,
+                DEFAULT_ASSET.into(),
+                money("0.5"),
+            ))
+            .unwrap();
+
+        let mut restored = Exchange::<Money>::restore_bin(&exchange.snapshot_bin()).unwrap();
+
+        assert_eq!(restored.clients, exchange.clients);
         assert_eq!(
-            clients.iter().find(|(&k, _)| k == 2).map(|(_, v)| *v),
-            Some(&Client {
-                funds_available: 1.0,
-                funds_held: 0.0,
-                locked: false,
-            })
+            restored.transaction_states().collect::<Vec<_>>(),
+            exchange.transaction_states().collect::<Vec<_>>()
         );
+        assert_eq!(restored.client_transactions(1), vec![1, 3]);
+        assert_eq!(restored.adjustments(), exchange.adjustments());
+        assert_eq!(restored.total_disputes(), 1);
+        assert_eq!(restored.config.max_clients, Some(2));
+        assert_eq!(restored.transfers(), exchange.transfers());
         assert_eq!(
-            clients.iter().find(|(&k, _)| k == 5).map(|(_, v)| *v),
-            Some(&Client {
-                funds_available: 4.0,
-                funds_held: 0.0,
-                locked: false,
-            })
+            restored.deposit(4, 1, DEFAULT_ASSET, money("1.0")),
+            Err(TransactionAlreadyExists)
         );
     }
 }
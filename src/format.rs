@@ -0,0 +1,51 @@
+use crate::money::Amount;
+use alloc::string::String;
+
+/// The decimal places amounts are written with unless others are chosen, the
+/// four the spec asks for.
+pub const DEFAULT_SCALE: u8 = 4;
+
+/// Formats the amount with exactly `scale` decimal places, rounding if needed,
+/// like `1.50` for 1.5 with a scale of 2. Amounts that round to zero are
+/// written without a sign, so there's never a `-0.00`.
+pub fn format_amount<M: Amount>(m: M, scale: u8) -> String {
+    let formatted = m.to_fixed(scale);
+
+    match formatted.strip_prefix('-') {
+        Some(unsigned) if unsigned.chars().all(|c| c == '0' || c == '.') => String::from(unsigned),
+        _ => formatted,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::money::money;
+
+    #[test]
+    fn format_amount_pads_to_scale() {
+        assert_eq!(format_amount(money("1.5"), 2), "1.50");
+        assert_eq!(format_amount(money("0.0"), 4), "0.0000");
+        assert_eq!(format_amount(money("-3.0"), 1), "-3.0");
+    }
+
+    #[test]
+    fn format_amount_rounds_to_scale() {
+        assert_eq!(format_amount(money("1.23456"), 4), "1.2346");
+        assert_eq!(format_amount(money("-1.26"), 1), "-1.3");
+        assert_eq!(format_amount(money("2.4"), 0), "2");
+        assert_eq!(format_amount(money("0.125"), 2), "0.12");
+    }
+
+    #[test]
+    fn format_amount_hides_float_noise() {
+        assert_eq!((0.3f32 + 0.6).to_string(), "0.90000004");
+        assert_eq!(format_amount(0.3f32 + 0.6, DEFAULT_SCALE), "0.9000");
+    }
+
+    #[test]
+    fn format_amount_drops_sign_of_zero() {
+        assert_eq!(format_amount(money("-0.0"), 2), "0.00");
+        assert_eq!(format_amount(money("-0.0001"), 2), "0.00");
+    }
+}
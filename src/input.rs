@@ -0,0 +1,949 @@
+use crate::client::{Asset, ClientId, DEFAULT_ASSET};
+use crate::money::Money;
+use crate::transaction::{Transaction, TransactionId};
+use csv::{Reader, ReaderBuilder, StringRecord, Trim};
+use serde::Deserialize;
+use std::convert::{TryFrom, TryInto};
+use std::error::Error;
+use std::fmt;
+use std::io::Read;
+
+/// This is a Data Transfer Object only used for CSV deserialization purposes.
+/// Columns it doesn't have a field for are ignored.
+#[derive(Deserialize)]
+pub struct TransactionDTO {
+    // "type" is a keyword, use "kind" instead
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub client: ClientId,
+    pub tx: TransactionId,
+    // kept as text so it can be parsed according to the `ParseOptions`
+    pub amount: Option<String>,
+    // the column is optional, transactions without it use the default asset
+    pub asset: Option<Asset>,
+    // the column is optional, in seconds since the Unix epoch
+    pub timestamp: Option<i64>,
+    // the column is only needed for transfers, it's the receiving client
+    pub to: Option<ClientId>,
+}
+
+impl TransactionDTO {
+    /// Converts the row into a transaction, parsing fields as configured.
+    pub fn parse(self, options: &ParseOptions) -> Result<Transaction, ParseError> {
+        // The serde+csv combination can't deserialize into filled enums(?). Do
+        // it manually instead.
+
+        let asset = self.asset.unwrap_or_else(|| String::from(DEFAULT_ASSET));
+        // an empty amount, quoted or not, is the same as a missing one
+        let amount = self.amount.as_deref().filter(|amount| !amount.is_empty());
+
+        let kind = self.kind.as_str();
+        if options.strict_disputes && matches!(kind, "dispute" | "resolve" | "chargeback") {
+            if let Some(amount) = amount {
+                return Err(ParseError::UnexpectedAmount(amount.to_owned()));
+            }
+        }
+
+        let amount = || parse_amount(amount.ok_or(ParseError::MissingAmount)?, options);
+
+        match kind {
+            "deposit" => Ok(Transaction::Deposit(self.client, self.tx, asset, amount()?)),
+            "withdrawal" => Ok(Transaction::Withdrawal(
+                self.client,
+                self.tx,
+                asset,
+                amount()?,
+            )),
+            "transfer" => {
+                let to = self
+                    .to
+                    .ok_or_else(|| ParseError::Invalid(String::from("missing 'to' field")))?;
+                Ok(Transaction::Transfer(
+                    self.client,
+                    to,
+                    self.tx,
+                    asset,
+                    amount()?,
+                ))
+            }
+            "dispute" => Ok(Transaction::Dispute(self.client, self.tx)),
+            "resolve" => Ok(Transaction::Resolve(self.client, self.tx)),
+            "chargeback" => Ok(Transaction::Chargeback(self.client, self.tx)),
+            "session" => Ok(Transaction::SessionBoundary(self.client, self.tx)),
+            "freeze" => Ok(Transaction::Freeze(self.client, self.tx)),
+            "unfreeze" => Ok(Transaction::Unfreeze(self.client, self.tx)),
+            kind => Err(ParseError::UnknownType(kind.to_owned())),
+        }
+    }
+}
+
+impl TryInto<Transaction> for TransactionDTO {
+    type Error = ParseError;
+    fn try_into(self) -> Result<Transaction, ParseError> {
+        self.parse(&ParseOptions::default())
+    }
+}
+
+/// Parses a single comma separated line without a header, like
+/// `deposit,1,5,1.0`, using the default `ParseOptions`. The fields are the
+/// type, client, tx, and, for deposits and withdrawals, the amount.
+impl TryFrom<&str> for Transaction {
+    type Error = ParseError;
+
+    fn try_from(line: &str) -> Result<Transaction, ParseError> {
+        let fields = line.split(',').map(str::trim).collect::<Vec<_>>();
+        if fields.len() < 3 || fields.len() > 4 {
+            return Err(ParseError::Invalid(format!(
+                "expected 3 or 4 fields, found {}",
+                fields.len()
+            )));
+        }
+
+        let client = fields[1]
+            .parse()
+            .map_err(|_| ParseError::Invalid(format!("invalid 'client' field '{}'", fields[1])))?;
+        let tx = fields[2]
+            .parse()
+            .map_err(|_| ParseError::Invalid(format!("invalid 'tx' field '{}'", fields[2])))?;
+        let amount = fields
+            .get(3)
+            .filter(|amount| !amount.is_empty())
+            .map(|amount| amount.to_string());
+
+        let row = TransactionDTO {
+            kind: fields[0].to_owned(),
+            client,
+            tx,
+            amount,
+            asset: None,
+            timestamp: None,
+            to: None,
+        };
+        row.parse(&ParseOptions::default())
+    }
+}
+
+/// Configures how the fields of a row are parsed.
+#[derive(Clone, Default)]
+pub struct ParseOptions {
+    /// Whether amounts may include currency symbols and grouping separators,
+    /// like `$1,000.50` or `1 000.50`.
+    pub lenient_amounts: bool,
+
+    /// The name of the column holding amounts, if it isn't `amount`.
+    pub amount_column: Option<String>,
+
+    /// Whether rows with a timestamp before the previous row's are rejected.
+    pub require_ordered: bool,
+
+    /// The most decimal places an amount may have, if limited.
+    pub scale: Option<u8>,
+
+    /// Whether dispute, resolve, and chargeback rows with an amount are
+    /// rejected rather than having it ignored.
+    pub strict_disputes: bool,
+
+    /// Whether amounts may use scientific notation, like `1e2`. Financial data
+    /// rarely does, so such amounts are more likely mistakes.
+    pub scientific_amounts: bool,
+
+    /// Whether client and transaction IDs may be written in hex, like `0x1f`,
+    /// or with underscores between digits, like `1_000`.
+    pub lenient_ids: bool,
+}
+
+/// The symbols that lenient parsing allows in front of an amount.
+const CURRENCY_SYMBOLS: &[char] = &['$', '€', '£', '¥'];
+
+/// The separators that lenient parsing allows between groups of digits.
+const GROUPING_SEPARATORS: &[char] = &[',', ' ', '\u{a0}', '_'];
+
+/// The characters that mark the exponent of an amount in scientific notation.
+const EXPONENT_MARKERS: &[char] = &['e', 'E'];
+
+/// Parses an amount. In lenient mode the currency symbols and grouping
+/// separators are stripped first; amounts with a comma must be quoted so it
+/// isn't read as a delimiter. Amounts with more decimal places than the scale
+/// allows are rejected rather than rounded, and so are amounts in scientific
+/// notation unless the options allow it.
+fn parse_amount(amount: &str, options: &ParseOptions) -> Result<Money, ParseError> {
+    let cleaned;
+    let text = if options.lenient_amounts {
+        cleaned = amount
+            .trim_start_matches(CURRENCY_SYMBOLS)
+            .replace(GROUPING_SEPARATORS, "");
+        cleaned.as_str()
+    } else {
+        amount
+    };
+
+    if !options.scientific_amounts && text.contains(EXPONENT_MARKERS) {
+        return Err(ParseError::Invalid(format!(
+            "invalid 'amount' field '{}', scientific notation isn't allowed",
+            amount
+        )));
+    }
+
+    if let Some(scale) = options.scale {
+        let decimals = text.split('.').nth(1).map_or(0, str::len);
+        if decimals > scale as usize {
+            return Err(ParseError::Invalid(format!(
+                "invalid 'amount' field '{}', more than {} decimal places",
+                amount, scale
+            )));
+        }
+    }
+
+    text.parse()
+        .map_err(|_| ParseError::InvalidAmount(amount.to_owned()))
+}
+
+/// The columns holding IDs, which lenient parsing accepts in other forms.
+const ID_COLUMNS: &[&str] = &["client", "tx", "to"];
+
+/// Parses an ID written in hex with a `0x` prefix or with underscores between
+/// digits, or returns `None` if it's neither.
+fn parse_lenient_id(id: &str) -> Option<u64> {
+    let digits = id.replace('_', "");
+
+    match digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => digits.parse().ok(),
+    }
+}
+
+/// Creates a CSV reader configured for transaction input.
+fn reader<R: Read>(reader: R) -> Reader<R> {
+    ReaderBuilder::new()
+        // remove whitespace when reading headers and values, otherwise they may
+        // be read incorrectly
+        .trim(Trim::All)
+        // allow rows to be different sizes (dispute, resolve, chargeback don't
+        // include an "amount" field)
+        .flexible(true)
+        .from_reader(reader)
+}
+
+/// An error for a row that couldn't be turned into a transaction.
+#[derive(Debug)]
+pub enum ParseError {
+    /// The row couldn't be read or its fields couldn't be deserialized.
+    Csv(csv::Error),
+
+    /// A deposit or withdrawal has no amount.
+    MissingAmount,
+
+    /// A dispute, resolve, or chargeback has an amount, which isn't allowed in
+    /// strict mode.
+    UnexpectedAmount(String),
+
+    /// The row's type isn't one of the known transaction types.
+    UnknownType(String),
+
+    /// The amount, as given, isn't a number.
+    InvalidAmount(String),
+
+    /// The row's fields don't make a valid transaction for another reason,
+    /// described by the message.
+    Invalid(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Csv(err) => write!(f, "{}", err),
+            ParseError::MissingAmount => write!(f, "missing 'amount' field"),
+            ParseError::UnexpectedAmount(amount) => {
+                write!(f, "unexpected 'amount' field '{}'", amount)
+            }
+            ParseError::UnknownType(kind) => write!(f, "unknown transaction type '{}'", kind),
+            ParseError::InvalidAmount(amount) => write!(f, "invalid 'amount' field '{}'", amount),
+            ParseError::Invalid(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl Error for ParseError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ParseError::Csv(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+/// Renames any configured columns in the headers to the names `TransactionDTO`
+/// expects.
+fn rename_headers(headers: &StringRecord, options: &ParseOptions) -> StringRecord {
+    let amount_column = options.amount_column.as_deref();
+
+    headers
+        .iter()
+        .map(|header| {
+            if Some(header) == amount_column {
+                "amount"
+            } else {
+                header
+            }
+        })
+        .collect()
+}
+
+/// Parses CSV input into transactions using the default `ParseOptions`.
+///
+/// Rows that can't be parsed are yielded as errors and the rest of the input
+/// is still read, unless reading itself failed.
+pub fn transactions<R: Read>(reader: R) -> Transactions<R> {
+    transactions_with_options(reader, ParseOptions::default())
+}
+
+/// Parses CSV input into transactions using the given `ParseOptions`.
+pub fn transactions_with_options<R: Read>(reader: R, options: ParseOptions) -> Transactions<R> {
+    Transactions {
+        reader: self::reader(reader),
+        headers: None,
+        record: StringRecord::new(),
+        options,
+        timestamp: None,
+        row_timestamp: None,
+        done: false,
+    }
+}
+
+/// An iterator over the transactions parsed from CSV input.
+pub struct Transactions<R> {
+    reader: Reader<R>,
+    headers: Option<StringRecord>,
+    record: StringRecord,
+    options: ParseOptions,
+    timestamp: Option<i64>,
+    row_timestamp: Option<i64>,
+    done: bool,
+}
+
+impl<R> Transactions<R> {
+    /// The line the most recently read row started on.
+    pub fn line(&self) -> u64 {
+        self.record.position().map_or(0, |position| position.line())
+    }
+
+    /// The latest timestamp read so far, if the input has any.
+    pub fn timestamp(&self) -> Option<i64> {
+        self.timestamp
+    }
+
+    /// The timestamp of the most recently read row, if it had one, e.g. to
+    /// pass to `Exchange::process_at` with the row's transaction.
+    pub fn row_timestamp(&self) -> Option<i64> {
+        self.row_timestamp
+    }
+
+    /// Describes a failure to deserialize one of the row's fields by naming the
+    /// field and its raw value, if the error is about a single field.
+    fn field_error(&self, err: &csv::Error) -> Option<String> {
+        let index = match err.kind() {
+            csv::ErrorKind::Deserialize { err, .. } => err.field()? as usize,
+            _ => return None,
+        };
+        let field = self.headers.as_ref()?.get(index)?;
+        let value = self.record.get(index)?;
+
+        Some(format!("invalid '{}' field '{}'", field, value))
+    }
+
+    /// Rewrites the row's IDs written in hex or with underscores as plain
+    /// numbers, so they can be deserialized. Anything else is left for
+    /// deserializing to reject.
+    fn normalize_ids(&mut self) {
+        let headers = match &self.headers {
+            Some(headers) => headers,
+            None => return,
+        };
+
+        let mut record = self
+            .record
+            .iter()
+            .zip(headers.iter())
+            .map(|(value, header)| match parse_lenient_id(value) {
+                Some(id) if ID_COLUMNS.contains(&header) => id.to_string(),
+                _ => value.to_owned(),
+            })
+            .collect::<StringRecord>();
+        // fields past the headers are kept as they are
+        for value in self.record.iter().skip(headers.len()) {
+            record.push_field(value);
+        }
+        record.set_position(self.record.position().cloned());
+
+        self.record = record;
+    }
+
+    /// Keeps track of the row's timestamp, rejecting it if it's out of order
+    /// and that isn't allowed.
+    fn check_timestamp(&mut self, timestamp: Option<i64>) -> Result<(), ParseError> {
+        self.row_timestamp = timestamp;
+        match (timestamp, self.timestamp) {
+            (Some(timestamp), Some(previous))
+                if timestamp < previous && self.options.require_ordered =>
+            {
+                Err(ParseError::Invalid(format!(
+                    "timestamp {} is before the previous one {}",
+                    timestamp, previous
+                )))
+            }
+            (Some(timestamp), _) => {
+                self.timestamp = Some(self.timestamp.map_or(timestamp, |t| t.max(timestamp)));
+                Ok(())
+            }
+            (None, _) => Ok(()),
+        }
+    }
+}
+
+impl<R: Read> Iterator for Transactions<R> {
+    type Item = Result<Transaction, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        if self.headers.is_none() {
+            match self.reader.headers() {
+                Ok(headers) => self.headers = Some(rename_headers(headers, &self.options)),
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(ParseError::Csv(err)));
+                }
+            }
+        }
+
+        self.row_timestamp = None;
+        match self.reader.read_record(&mut self.record) {
+            Ok(true) => {}
+            Ok(false) => {
+                self.done = true;
+                return None;
+            }
+            Err(err) => {
+                // there's no recovering from failing to read at all
+                self.done = err.is_io_error();
+                return Some(Err(ParseError::Csv(err)));
+            }
+        }
+
+        if self.options.lenient_ids {
+            self.normalize_ids();
+        }
+
+        let row = match self
+            .record
+            .deserialize::<TransactionDTO>(self.headers.as_ref())
+        {
+            Ok(row) => row,
+            Err(err) => {
+                let err = match self.field_error(&err) {
+                    Some(message) => ParseError::Invalid(message),
+                    None => ParseError::Csv(err),
+                };
+                return Some(Err(err));
+            }
+        };
+
+        if let Err(err) = self.check_timestamp(row.timestamp) {
+            return Some(Err(err));
+        }
+
+        Some(row.parse(&self.options))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::money::money;
+
+    fn parse_rows(input: &str, options: ParseOptions) -> Vec<Result<Transaction, String>> {
+        transactions_with_options(input.as_bytes(), options)
+            .map(|transaction| transaction.map_err(|err| err.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn transactions_parses_every_type() {
+        let input = "type, client, tx, amount\n\
+                     deposit, 1, 1, 1.0\n\
+                     withdrawal, 1, 2, 0.5\n\
+                     dispute, 1, 1,\n\
+                     resolve, 1, 1\n\
+                     chargeback, 1, 1,\n\
+                     session, 1, 3,\n\
+                     freeze, 1, 4,\n\
+                     unfreeze, 1, 5,\n";
+        let transactions = parse_rows(input, ParseOptions::default());
+
+        assert_eq!(
+            transactions,
+            vec![
+                Ok(Transaction::Deposit(
+                    1,
+                    1,
+                    DEFAULT_ASSET.into(),
+                    money("1.0")
+                )),
+                Ok(Transaction::Withdrawal(
+                    1,
+                    2,
+                    DEFAULT_ASSET.into(),
+                    money("0.5")
+                )),
+                Ok(Transaction::Dispute(1, 1)),
+                Ok(Transaction::Resolve(1, 1)),
+                Ok(Transaction::Chargeback(1, 1)),
+                Ok(Transaction::SessionBoundary(1, 3)),
+                Ok(Transaction::Freeze(1, 4)),
+                Ok(Transaction::Unfreeze(1, 5)),
+            ]
+        );
+    }
+
+    #[test]
+    fn transactions_parses_transfers_with_a_to_column() {
+        let input = "type, client, tx, amount, to\n\
+                     deposit, 1, 1, 1.0,\n\
+                     transfer, 1, 2, 0.5, 2\n\
+                     transfer, 1, 3, 0.5,\n";
+        let transactions = parse_rows(input, ParseOptions::default());
+
+        assert_eq!(
+            transactions,
+            vec![
+                Ok(Transaction::Deposit(
+                    1,
+                    1,
+                    DEFAULT_ASSET.into(),
+                    money("1.0")
+                )),
+                Ok(Transaction::Transfer(
+                    1,
+                    2,
+                    2,
+                    DEFAULT_ASSET.into(),
+                    money("0.5")
+                )),
+                Err(String::from("missing 'to' field")),
+            ]
+        );
+    }
+
+    #[test]
+    fn transactions_parses_quoted_fields() {
+        let input = "\"type\",\"client\",\"tx\",\"amount\"\n\
+                     \"deposit\",\"1\",\"5\",\"1.0\"\n\
+                     \"dispute\",\"1\",\"5\",\"\"\n\
+                     \"resolve\",\"1\",\"5\",\" \"\n";
+        let transactions = parse_rows(input, ParseOptions::default());
+
+        assert_eq!(
+            transactions,
+            vec![
+                Ok(Transaction::Deposit(
+                    1,
+                    5,
+                    DEFAULT_ASSET.into(),
+                    money("1.0")
+                )),
+                Ok(Transaction::Dispute(1, 5)),
+                Ok(Transaction::Resolve(1, 5)),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_ignores_amounts_on_disputes_by_default() {
+        let input = "type,client,tx,amount\ndispute,1,1,1.0\nresolve,1,1,\n";
+        let transactions = parse_rows(input, ParseOptions::default());
+
+        assert_eq!(
+            transactions,
+            vec![
+                Ok(Transaction::Dispute(1, 1)),
+                Ok(Transaction::Resolve(1, 1))
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_rejects_amounts_on_disputes_when_strict() {
+        let input = "type,client,tx,amount\ndispute,1,1,1.0\nresolve,1,1,\n";
+        let options = ParseOptions {
+            strict_disputes: true,
+            ..ParseOptions::default()
+        };
+        let transactions = parse_rows(input, options);
+
+        assert_eq!(
+            transactions,
+            vec![
+                Err(String::from("unexpected 'amount' field '1.0'")),
+                Ok(Transaction::Resolve(1, 1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn transactions_ignores_extra_columns() {
+        let input = "type,client,tx,amount,timestamp\n\
+                     deposit,1,1,1.0,1600000000\n\
+                     dispute,1,1,,1600000001\n";
+        let transactions = parse_rows(input, ParseOptions::default());
+
+        assert_eq!(
+            transactions,
+            vec![
+                Ok(Transaction::Deposit(
+                    1,
+                    1,
+                    DEFAULT_ASSET.into(),
+                    money("1.0")
+                )),
+                Ok(Transaction::Dispute(1, 1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn transactions_accepts_ordered_timestamps() {
+        let input = "type,client,tx,amount,timestamp\n\
+                     deposit,1,1,1.0,100\n\
+                     deposit,1,2,1.0,100\n\
+                     deposit,1,3,1.0,\n\
+                     deposit,1,4,1.0,101\n";
+        let options = ParseOptions {
+            require_ordered: true,
+            ..ParseOptions::default()
+        };
+        let mut transactions = transactions_with_options(input.as_bytes(), options);
+
+        assert_eq!(transactions.by_ref().filter(Result::is_ok).count(), 4);
+        assert_eq!(transactions.timestamp(), Some(101));
+    }
+
+    #[test]
+    fn row_timestamp_is_the_current_rows() {
+        let input = "type,client,tx,amount,timestamp
+                     deposit,1,1,1.0,100
+                     deposit,1,2,1.0,
+                     deposit,1,3,1.0,99
+";
+        let mut transactions = transactions(input.as_bytes());
+
+        let mut timestamps = Vec::new();
+        while transactions.next().is_some() {
+            timestamps.push(transactions.row_timestamp());
+        }
+
+        assert_eq!(timestamps, vec![Some(100), None, Some(99)]);
+        assert_eq!(transactions.timestamp(), Some(100));
+    }
+
+    #[test]
+    fn transactions_rejects_out_of_order_timestamps_if_required() {
+        let input = "type,client,tx,amount,timestamp\n\
+                     deposit,1,1,1.0,100\n\
+                     deposit,1,2,1.0,99\n\
+                     deposit,1,3,1.0,100\n";
+        let options = ParseOptions {
+            require_ordered: true,
+            ..ParseOptions::default()
+        };
+
+        assert_eq!(
+            parse_rows(input, options),
+            vec![
+                Ok(Transaction::Deposit(
+                    1,
+                    1,
+                    DEFAULT_ASSET.into(),
+                    money("1.0")
+                )),
+                Err(String::from("timestamp 99 is before the previous one 100")),
+                Ok(Transaction::Deposit(
+                    1,
+                    3,
+                    DEFAULT_ASSET.into(),
+                    money("1.0")
+                )),
+            ]
+        );
+        assert!(parse_rows(input, ParseOptions::default())
+            .iter()
+            .all(Result::is_ok));
+    }
+
+    #[test]
+    fn transactions_names_invalid_fields_and_skips_the_row() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,abc\n\
+                     deposit,x,2,1.0\n\
+                     deposit,1,-3,1.0\n\
+                     deposit,1,4,1.0\n";
+        let transactions = parse_rows(input, ParseOptions::default());
+
+        assert_eq!(
+            transactions,
+            vec![
+                Err(String::from("invalid 'amount' field 'abc'")),
+                Err(String::from("invalid 'client' field 'x'")),
+                Err(String::from("invalid 'tx' field '-3'")),
+                Ok(Transaction::Deposit(
+                    1,
+                    4,
+                    DEFAULT_ASSET.into(),
+                    money("1.0")
+                )),
+            ]
+        );
+    }
+
+    #[test]
+    fn transactions_can_be_composed() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,1.0\n\
+                     deposit,2,2,2.0\n\
+                     deposit,1,3,3.0\n";
+        let clients = transactions(input.as_bytes())
+            .filter_map(Result::ok)
+            .filter(|transaction| transaction.client() == 1)
+            .map(|transaction| transaction.tx_id())
+            .collect::<Vec<_>>();
+
+        assert_eq!(clients, vec![1, 3]);
+    }
+
+    #[test]
+    fn parse_rejects_formatted_amounts_by_default() {
+        let input = "type,client,tx,amount\ndeposit,1,1,\"$1,000.50\"\ndeposit,1,2,1 000.50\n";
+        let transactions = parse_rows(input, ParseOptions::default());
+
+        assert_eq!(
+            transactions,
+            vec![
+                Err(String::from("invalid 'amount' field '$1,000.50'")),
+                Err(String::from("invalid 'amount' field '1 000.50'")),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_accepts_formatted_amounts_when_lenient() {
+        let input = "type,client,tx,amount\ndeposit,1,1,\"$1,000.50\"\nwithdrawal,1,2,1 000.50\n";
+        let options = ParseOptions {
+            lenient_amounts: true,
+            ..ParseOptions::default()
+        };
+        let transactions = parse_rows(input, options);
+
+        assert_eq!(
+            transactions,
+            vec![
+                Ok(Transaction::Deposit(
+                    1,
+                    1,
+                    DEFAULT_ASSET.into(),
+                    money("1000.50")
+                )),
+                Ok(Transaction::Withdrawal(
+                    1,
+                    2,
+                    DEFAULT_ASSET.into(),
+                    money("1000.50")
+                )),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_accepts_hex_and_underscored_ids_when_lenient() {
+        let input = "type,client,tx,amount\n\
+                     deposit,0x10,1_000,1.0\n\
+                     dispute,1_6,0X3E8\n";
+        let options = ParseOptions {
+            lenient_ids: true,
+            ..ParseOptions::default()
+        };
+        let transactions = parse_rows(input, options);
+
+        assert_eq!(
+            transactions,
+            vec![
+                Ok(Transaction::Deposit(
+                    16,
+                    1000,
+                    DEFAULT_ASSET.into(),
+                    money("1.0")
+                )),
+                Ok(Transaction::Dispute(16, 1000)),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_rejects_underscored_ids_by_default() {
+        let input = "type,client,tx,amount\ndeposit,1_6,1,1.0\ndeposit,1,1_000,1.0\n";
+        let transactions = parse_rows(input, ParseOptions::default());
+
+        assert_eq!(
+            transactions,
+            vec![
+                Err(String::from("invalid 'client' field '1_6'")),
+                Err(String::from("invalid 'tx' field '1_000'")),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_rejects_scientific_notation_by_default() {
+        let input = "type,client,tx,amount\ndeposit,1,1,1e2\n";
+        let transactions = parse_rows(input, ParseOptions::default());
+
+        assert_eq!(
+            transactions,
+            vec![Err(String::from(
+                "invalid 'amount' field '1e2', scientific notation isn't allowed"
+            ))]
+        );
+    }
+
+    #[test]
+    fn parse_accepts_scientific_notation_when_allowed() {
+        let input = "type,client,tx,amount\ndeposit,1,1,1e2\n";
+        let options = ParseOptions {
+            scientific_amounts: true,
+            ..ParseOptions::default()
+        };
+        let transactions = parse_rows(input, options);
+
+        assert_eq!(
+            transactions,
+            vec![Ok(Transaction::Deposit(
+                1,
+                1,
+                DEFAULT_ASSET.into(),
+                money("100.0")
+            ))]
+        );
+    }
+
+    #[test]
+    fn parse_rejects_amounts_beyond_scale() {
+        let input = "type,client,tx,amount\ndeposit,1,1,1.50\ndeposit,1,2,1.505\n";
+        let options = ParseOptions {
+            scale: Some(2),
+            ..ParseOptions::default()
+        };
+        let transactions = parse_rows(input, options);
+
+        assert_eq!(
+            transactions,
+            vec![
+                Ok(Transaction::Deposit(
+                    1,
+                    1,
+                    DEFAULT_ASSET.into(),
+                    money("1.5")
+                )),
+                Err(String::from(
+                    "invalid 'amount' field '1.505', more than 2 decimal places"
+                )),
+            ]
+        );
+    }
+
+    #[test]
+    fn transactions_reads_amounts_from_configured_column() {
+        let input = "type,client,tx,value\ndeposit,1,1,1.5\ndispute,1,1,\n";
+        let options = ParseOptions {
+            amount_column: Some(String::from("value")),
+            ..ParseOptions::default()
+        };
+        let transactions = parse_rows(input, options);
+
+        assert_eq!(
+            transactions,
+            vec![
+                Ok(Transaction::Deposit(
+                    1,
+                    1,
+                    DEFAULT_ASSET.into(),
+                    money("1.5")
+                )),
+                Ok(Transaction::Dispute(1, 1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn try_from_line_parses_every_type() {
+        let transactions = [
+            "deposit,1,5,1.0",
+            "withdrawal, 1, 6, 0.5",
+            "dispute,1,5",
+            "resolve,1,5,",
+            "chargeback,1,5",
+        ]
+        .iter()
+        .map(|&line| Transaction::try_from(line).map_err(|err| err.to_string()))
+        .collect::<Vec<_>>();
+
+        assert_eq!(
+            transactions,
+            vec![
+                Ok(Transaction::Deposit(
+                    1,
+                    5,
+                    DEFAULT_ASSET.into(),
+                    money("1.0")
+                )),
+                Ok(Transaction::Withdrawal(
+                    1,
+                    6,
+                    DEFAULT_ASSET.into(),
+                    money("0.5")
+                )),
+                Ok(Transaction::Dispute(1, 5)),
+                Ok(Transaction::Resolve(1, 5)),
+                Ok(Transaction::Chargeback(1, 5)),
+            ]
+        );
+    }
+
+    #[test]
+    fn try_from_line_rejects_malformed_lines() {
+        let error = |line: &str| Transaction::try_from(line).unwrap_err().to_string();
+
+        assert_eq!(error("deposit,1"), "expected 3 or 4 fields, found 2");
+        assert_eq!(error("deposit,x,5,1.0"), "invalid 'client' field 'x'");
+        assert_eq!(error("deposit,1,5"), "missing 'amount' field");
+        assert_eq!(error("refund,1,5,1.0"), "unknown transaction type 'refund'");
+    }
+
+    #[test]
+    fn try_from_line_reports_specific_errors() {
+        assert!(matches!(
+            Transaction::try_from("withdrawal,1,5,"),
+            Err(ParseError::MissingAmount)
+        ));
+        assert!(matches!(
+            Transaction::try_from("refund,1,5,1.0"),
+            Err(ParseError::UnknownType(kind)) if kind == "refund"
+        ));
+        assert!(matches!(
+            Transaction::try_from("deposit,1,5,abc"),
+            Err(ParseError::InvalidAmount(amount)) if amount == "abc"
+        ));
+    }
+}
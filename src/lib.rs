@@ -0,0 +1,26 @@
+//! A payments engine that processes deposits, withdrawals, and the dispute
+//! process for client accounts.
+//!
+//! The `exchange` module holds the engine itself and is agnostic to the input
+//! format; the `input` module parses CSV input into transactions for it. The
+//! engine is generic over the money type, see the `money` module, and the
+//! `format` module writes amounts consistently.
+//!
+//! Only the `input` module needs the standard library. Without the `std`
+//! feature (on by default) the engine builds as `no_std` with `alloc`.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+pub mod client;
+pub mod collections;
+pub mod exchange;
+pub mod format;
+#[cfg(feature = "std")]
+pub mod input;
+pub mod money;
+pub mod transaction;
+
+#[cfg(feature = "std")]
+pub use input::{transactions, ParseError};
@@ -1,106 +1,2471 @@
-use csv::{ReaderBuilder, Trim, Writer};
-use serde::{Deserialize, Serialize};
-use std::convert::TryInto;
-use std::fs::File;
-use std::{env, io};
-
-pub mod client;
-pub mod exchange;
-pub mod transaction;
-
-use crate::client::{Client, ClientId};
-use crate::exchange::Exchange;
-use crate::transaction::{Transaction, TransactionId};
-
-/// This is a Data Transfer Object only used for CSV deserialization purposes.
-#[derive(Deserialize)]
-pub struct TransactionDTO {
-    // "type" is a keyword, use "kind" instead
-    #[serde(rename = "type")]
-    pub kind: String,
-    pub client: ClientId,
-    pub tx: TransactionId,
-    pub amount: Option<f32>,
-}
-
-impl TryInto<Transaction> for TransactionDTO {
-    type Error = String;
-    fn try_into(self) -> Result<Transaction, String> {
-        // The serde+csv combination can't deserialize into filled enums(?). Do
-        // it manually instead.
-
-        match self.kind.as_str() {
-            "deposit" => {
-                let amount = self.amount.ok_or(String::from("missing 'amount' field"))?;
-                Ok(Transaction::Deposit(self.client, self.tx, amount))
-            }
-            "withdrawal" => {
-                let amount = self.amount.ok_or(String::from("missing 'amount' field"))?;
-                Ok(Transaction::Withdrawal(self.client, self.tx, amount))
-            }
-            "dispute" => Ok(Transaction::Dispute(self.client, self.tx)),
-            "resolve" => Ok(Transaction::Resolve(self.client, self.tx)),
-            "chargeback" => Ok(Transaction::Chargeback(self.client, self.tx)),
-            _ => Err(String::from("unknown transaction type")),
-        }
-    }
-}
+use csv::{Terminator, Writer, WriterBuilder};
+use rust_coding_test::client::{Balances, Client, ClientId, DEFAULT_ASSET};
+use rust_coding_test::exchange::{
+    Exchange, ExchangeBuilder, ExchangeError, ProcessOutcome, TransactionStatus,
+};
+use rust_coding_test::format::{format_amount, DEFAULT_SCALE};
+use rust_coding_test::input::{self, ParseError, ParseOptions};
+use rust_coding_test::money::{Amount, Money};
+use rust_coding_test::transaction::{Transaction, TransactionId};
+use serde::ser::SerializeStruct;
+use serde::{Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+#[cfg(any(feature = "service", test))]
+use std::net::{Ipv4Addr, SocketAddr};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{env, fmt, io, process};
+
+/// The header `ClientDTO` rows are written under, for when there are no rows
+/// to take it from.
+const CLIENT_HEADER: &[&str] = &["client", "available", "held", "total", "locked"];
+
+/// Every column the client summary can have, for `--columns`.
+const CLIENT_COLUMNS: &[&str] = &[
+    "client",
+    "asset",
+    "available",
+    "held",
+    "total",
+    "locked",
+    "net",
+];
 
 /// This is a Data Transfer Object only used for CSV serialization purposes.
+/// There is one row per client per asset.
 #[derive(Serialize)]
-struct ClientDTO {
+struct ClientDTO<'a> {
     client: ClientId,
-    available: f32,
-    held: f32,
-    total: f32,
+    // only written when the input used assets, so single-asset output is
+    // unchanged
+    #[serde(skip_serializing_if = "Option::is_none")]
+    asset: Option<&'a str>,
+    available: AmountDTO,
+    held: AmountDTO,
+    total: AmountDTO,
     locked: bool,
+    // only written with `--with-net`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    net: Option<AmountDTO>,
 }
 
-impl ClientDTO {
-    fn new(id: &ClientId, client: &Client) -> ClientDTO {
+impl<'a> ClientDTO<'a> {
+    fn new(
+        id: &ClientId,
+        asset: Option<&'a str>,
+        balances: &Balances,
+        locked: bool,
+        scale: u8,
+        held_scale: u8,
+    ) -> ClientDTO<'a> {
         ClientDTO {
             client: *id,
-            available: client.funds_available,
-            held: client.funds_held,
-            total: client.funds_total(),
-            locked: client.locked,
+            asset,
+            available: AmountDTO(balances.funds_available, scale),
+            held: AmountDTO(balances.funds_held, held_scale),
+            total: AmountDTO(balances.funds_total(), scale),
+            locked,
+            net: None,
+        }
+    }
+
+    /// The row's value for the column, as written in the table of `--pretty`.
+    /// The column must be in `CLIENT_COLUMNS`.
+    fn cell(&self, column: &str) -> String {
+        match column {
+            "client" => self.client.to_string(),
+            "asset" => self.asset.unwrap_or_default().to_owned(),
+            "available" => self.available.to_string(),
+            "held" => self.held.to_string(),
+            "total" => self.total.to_string(),
+            "locked" => self.locked.to_string(),
+            "net" => self
+                .net
+                .as_ref()
+                .map_or_else(String::new, ToString::to_string),
+            _ => unreachable!("unknown column '{}'", column),
         }
     }
 }
 
-fn main() {
-    let input_filename = env::args().nth(1).expect("no filename provided");
-    let input_file = File::open(input_filename).expect("could not open file");
-    let mut input = ReaderBuilder::new()
-        // remove whitespace when reading headers and values, otherwise they may
-        // be read incorrectly
-        .trim(Trim::All)
-        // allow rows to be different sizes (dispute, resolve, chargeback don't
-        // include an "amount" field)
-        .flexible(true)
-        .from_reader(input_file);
-
-    let mut exchange = Exchange::new();
-
-    for row in input.deserialize::<TransactionDTO>() {
-        let transaction = row
-            .expect("failed to read row")
-            .try_into()
-            .expect("failed to read row");
-
-        match exchange.process(transaction) {
-            Err(_) => {
-                // just swallow logs for now, in the long term they should be
-                // logged somewhere.
+/// A client summary row with only the chosen columns, in their order. The
+/// columns must all be in `CLIENT_COLUMNS`.
+struct ColumnsDTO<'a> {
+    row: ClientDTO<'a>,
+    columns: &'a [&'static str],
+}
+
+impl Serialize for ColumnsDTO<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let row = &self.row;
+        let mut state = serializer.serialize_struct("ClientDTO", self.columns.len())?;
+
+        for &column in self.columns {
+            match column {
+                "client" => state.serialize_field(column, &row.client)?,
+                "asset" => state.serialize_field(column, &row.asset)?,
+                "available" => state.serialize_field(column, &row.available)?,
+                "held" => state.serialize_field(column, &row.held)?,
+                "total" => state.serialize_field(column, &row.total)?,
+                "locked" => state.serialize_field(column, &row.locked)?,
+                "net" => state.serialize_field(column, &row.net)?,
+                _ => unreachable!("unknown column '{}'", column),
+            }
+        }
+
+        state.end()
+    }
+}
+
+/// An amount as written to the output, always with a fixed number of decimal
+/// places. A float's own formatting would show its rounding errors, like
+/// `0.30000001` for 0.1 + 0.2.
+struct AmountDTO(Money, u8);
+
+impl fmt::Display for AmountDTO {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", format_amount(self.0, self.1))
+    }
+}
+
+impl Serialize for AmountDTO {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format_amount(self.0, self.1))
+    }
+}
+
+/// This is a Data Transfer Object only used for CSV serialization purposes.
+#[derive(Serialize)]
+struct TransactionStateDTO {
+    tx: TransactionId,
+    state: &'static str,
+}
+
+impl TransactionStateDTO {
+    fn new(tx: TransactionId, status: TransactionStatus) -> TransactionStateDTO {
+        let state = match status {
+            TransactionStatus::Completed => "completed",
+            TransactionStatus::Disputed => "disputed",
+            TransactionStatus::Resolved => "resolved",
+            TransactionStatus::ChargedBack => "chargedback",
+        };
+
+        TransactionStateDTO { tx, state }
+    }
+}
+
+/// What the program does with the transactions, chosen by the first argument.
+#[derive(Debug, PartialEq)]
+enum Command {
+    /// Processes the transactions and writes the client summary. It's the
+    /// default when no command is given.
+    Process,
+
+    /// Processes the transactions and only reports the rows that were
+    /// rejected, failing if there were any.
+    Validate,
+
+    /// Processes the transactions and only prints statistics about them.
+    Stats,
+
+    /// Processes the transactions and saves the exchange's state to the path,
+    /// see `Exchange::snapshot_bin`.
+    Snapshot(String),
+}
+
+// deriving it needs `#[default]`, which is newer than the Rust this supports
+#[allow(clippy::derivable_impls)]
+impl Default for Command {
+    fn default() -> Command {
+        Command::Process
+    }
+}
+
+/// The options that only make sense with `process`, since the other commands
+/// don't write the client summary.
+const PROCESS_FLAGS: &[&str] = &[
+    "--dump-transactions",
+    "--print-checksum",
+    "--pretty",
+    "--with-net",
+    "--with-metadata",
+    "--columns",
+    "--precision",
+    "--held-precision",
+    "--output",
+    "--append",
+    "--output-dir",
+    "--listen",
+];
+
+/// The options provided on the command line.
+#[derive(Default)]
+struct Options {
+    command: Command,
+
+    input_filename: String,
+
+    /// Where to write the final state of every transaction, if anywhere.
+    dump_transactions: Option<String>,
+
+    /// Whether to print a checksum of the client summary to stderr.
+    print_checksum: bool,
+
+    /// Whether to print how long processing took to stderr.
+    stats: bool,
+
+    /// Whether to print every processed transaction and its effect to stderr.
+    trace: bool,
+
+    /// Whether to print every rejected row to stderr as a JSON object instead
+    /// of as text.
+    errors_json: bool,
+
+    /// Whether to write the client summary as an aligned table instead of CSV.
+    pretty: bool,
+
+    /// Whether the client summary has a column with each client's deposits
+    /// minus withdrawals.
+    with_net: bool,
+
+    /// Whether the client summary starts with a comment line describing the
+    /// run.
+    with_metadata: bool,
+
+    /// Whether it's an error for any client to end up with negative available
+    /// funds.
+    forbid_negative: bool,
+
+    /// Whether the input is known to have no disputes, so transactions don't
+    /// need to be recorded.
+    no_disputes: bool,
+
+    /// Whether transactions for client 0 are rejected.
+    forbid_client_zero: bool,
+
+    /// How many seconds a dispute may stay open before it's resolved, by the
+    /// rows' timestamps, if limited.
+    dispute_window: Option<i64>,
+
+    /// How many transactions to process between checking that the balances
+    /// match the history, if at all.
+    self_check: Option<u64>,
+
+    /// How many transactions to process between printing the running count to
+    /// stderr, if at all.
+    progress: Option<u64>,
+
+    /// The most rows to process, if limited.
+    limit: Option<u64>,
+
+    /// Whether rows that can't be parsed count towards the limit.
+    limit_counts_errors: bool,
+
+    /// The only clients whose transactions are processed, if restricted.
+    only_clients: Option<HashSet<ClientId>>,
+
+    /// Where to write the client summary instead of stdout, if anywhere.
+    output: Option<String>,
+
+    /// Whether to append the client summary to the output file instead of
+    /// replacing it.
+    append: bool,
+
+    /// The directory to write a separate summary file for each client into
+    /// instead of a single summary, if any.
+    output_dir: Option<String>,
+
+    /// The address to accept connections sending transactions on instead of
+    /// reading a file, if any.
+    listen: Option<String>,
+
+    /// The decimal places to write available, total, and net amounts with, if
+    /// set. See `Options::output_scales`.
+    precision: Option<u8>,
+
+    /// The decimal places to write held amounts with, if set.
+    held_precision: Option<u8>,
+
+    /// The only columns the client summary has, in the order they're written,
+    /// if chosen.
+    columns: Option<Vec<&'static str>>,
+
+    parse: ParseOptions,
+}
+
+impl Options {
+    fn parse(args: impl Iterator<Item = String>) -> Result<Options, String> {
+        let mut options = Options::default();
+        let mut input_filename = None;
+        let mut snapshot_path = None;
+        let mut process_flag = None;
+
+        let mut args = args.peekable();
+        let command = match args.peek().map(String::as_str) {
+            Some("process") | Some("validate") | Some("stats") | Some("snapshot") => args.next(),
+            _ => None,
+        };
+
+        while let Some(arg) = args.next() {
+            if PROCESS_FLAGS.contains(&arg.as_str()) {
+                process_flag = Some(arg.clone());
+            }
+            let mut value = || args.next().ok_or(format!("missing value for '{}'", arg));
+
+            match arg.as_str() {
+                "--dump-transactions" => options.dump_transactions = Some(value()?),
+                "--print-checksum" => options.print_checksum = true,
+                "--stats" => options.stats = true,
+                "--trace" => options.trace = true,
+                "--errors-json" => options.errors_json = true,
+                "--pretty" => options.pretty = true,
+                "--with-net" => options.with_net = true,
+                "--with-metadata" => options.with_metadata = true,
+                "--forbid-negative" => options.forbid_negative = true,
+                "--no-disputes" => options.no_disputes = true,
+                "--forbid-client-zero" => options.forbid_client_zero = true,
+                "--dispute-window" => {
+                    let window = value()?;
+                    match window.parse() {
+                        Ok(window) if window > 0 => options.dispute_window = Some(window),
+                        _ => return Err(format!("invalid dispute window '{}'", window)),
+                    }
+                }
+                "--self-check" => {
+                    let interval = value()?;
+                    match interval.parse() {
+                        Ok(interval) if interval > 0 => options.self_check = Some(interval),
+                        _ => return Err(format!("invalid self-check interval '{}'", interval)),
+                    }
+                }
+                "--progress" => options.progress = Some(PROGRESS_INTERVAL),
+                "--limit" => {
+                    let limit = value()?;
+                    let limit = limit
+                        .parse()
+                        .map_err(|_| format!("invalid limit '{}'", limit))?;
+                    options.limit = Some(limit);
+                }
+                "--limit-counts-errors" => options.limit_counts_errors = true,
+                "--only-clients" => {
+                    let clients = value()?
+                        .split(',')
+                        .map(|id| {
+                            let id = id.trim();
+                            id.parse().map_err(|_| format!("invalid client '{}'", id))
+                        })
+                        .collect::<Result<_, _>>()?;
+                    options.only_clients = Some(clients);
+                }
+                "--lenient-amounts" => options.parse.lenient_amounts = true,
+                "--strict-disputes" => options.parse.strict_disputes = true,
+                "--scientific-amounts" => options.parse.scientific_amounts = true,
+                "--lenient-ids" => options.parse.lenient_ids = true,
+                "--amount-column" => options.parse.amount_column = Some(value()?),
+                "--require-ordered" => options.parse.require_ordered = true,
+                "--scale" => {
+                    let scale = value()?;
+                    let scale = scale
+                        .parse()
+                        .map_err(|_| format!("invalid scale '{}'", scale))?;
+                    options.parse.scale = Some(scale);
+                }
+                "--precision" | "--held-precision" => {
+                    let precision = value()?;
+                    let precision = precision
+                        .parse()
+                        .map_err(|_| format!("invalid precision '{}'", precision))?;
+                    if arg == "--precision" {
+                        options.precision = Some(precision);
+                    } else {
+                        options.held_precision = Some(precision);
+                    }
+                }
+                "--columns" => {
+                    let columns = value()?
+                        .split(',')
+                        .map(|name| {
+                            let name = name.trim();
+                            CLIENT_COLUMNS
+                                .iter()
+                                .find(|&&column| column == name)
+                                .copied()
+                                .ok_or(format!("unknown column '{}'", name))
+                        })
+                        .collect::<Result<Vec<_>, _>>()?;
+                    // the net deposits are only worked out if asked for
+                    options.with_net |= columns.contains(&"net");
+                    options.columns = Some(columns);
+                }
+                "--output" => options.output = Some(value()?),
+                "--append" => options.append = true,
+                "--output-dir" => options.output_dir = Some(value()?),
+                "--listen" => options.listen = Some(value()?),
+                "--to" => snapshot_path = Some(value()?),
+                _ if arg.starts_with("--") => return Err(format!("unknown option '{}'", arg)),
+                _ => input_filename = Some(arg),
+            }
+        }
+
+        options.command = match command.as_deref() {
+            None | Some("process") => Command::Process,
+            Some("validate") => Command::Validate,
+            Some("stats") => Command::Stats,
+            Some(_) => Command::Snapshot(snapshot_path.take().ok_or("'snapshot' requires '--to'")?),
+        };
+        if snapshot_path.is_some() {
+            return Err(String::from("'--to' is only for 'snapshot'"));
+        }
+        if let Some(flag) = process_flag {
+            if options.command != Command::Process {
+                return Err(format!("'{}' is only for 'process'", flag));
             }
-            _ => {}
         }
+
+        if options.append && options.output.is_none() {
+            return Err(String::from("'--append' requires '--output'"));
+        }
+        // without a history there's nothing to check the balances against
+        if options.self_check.is_some() && options.no_disputes {
+            return Err(String::from(
+                "'--self-check' can't be used with '--no-disputes'",
+            ));
+        }
+        // listening only ever writes the client summary
+        if options.listen.is_some() {
+            let unused = [
+                ("--stats", options.stats),
+                ("--forbid-negative", options.forbid_negative),
+                ("--output", options.output.is_some()),
+                ("--output-dir", options.output_dir.is_some()),
+                ("--print-checksum", options.print_checksum),
+                ("--dump-transactions", options.dump_transactions.is_some()),
+                ("--with-metadata", options.with_metadata),
+            ];
+            if let Some((flag, _)) = unused.iter().find(|&&(_, used)| used) {
+                return Err(format!("'{}' can't be used with '--listen'", flag));
+            }
+        }
+        if options.output.is_some() && options.output_dir.is_some() {
+            return Err(String::from(
+                "'--output' and '--output-dir' can't be used together",
+            ));
+        }
+
+        // there's no file to read when listening for connections
+        if options.listen.is_none() {
+            options.input_filename = input_filename.ok_or("no filename provided")?;
+        }
+        Ok(options)
+    }
+
+    /// The decimal places the client summary's amounts are written with:
+    /// first for available, total, and net amounts, then for held ones. A
+    /// precision that isn't given falls back to the scale, or
+    /// `DEFAULT_SCALE` if there's none.
+    fn output_scales(&self) -> (u8, u8) {
+        let scale = |precision: Option<u8>| precision.or(self.parse.scale).unwrap_or(DEFAULT_SCALE);
+        (scale(self.precision), scale(self.held_precision))
+    }
+}
+
+/// Creates a CSV writer that always ends lines with `\n`, so the output is the
+/// same on every platform. The header is only written if `headers` is set.
+fn writer<W: io::Write>(writer: W, headers: bool) -> Writer<W> {
+    WriterBuilder::new()
+        .terminator(Terminator::Any(b'\n'))
+        .has_headers(headers)
+        .from_writer(writer)
+}
+
+/// Writes the summary of every client's funds, one row at a time so the
+/// clients are never all collected. Rows are ordered by asset within each
+/// client, and clients come in the exchange's order, which is by ID for
+/// exchanges keeping clients ordered like the ones `exchange` creates. Amounts
+/// are written with the options' output scales, and the net column is added if
+/// the options ask for it. If the options choose the columns, rows only have
+/// those. The header is only written if `headers` is set.
+fn write_clients<W: io::Write>(
+    exchange: &Exchange,
+    writer: W,
+    options: &Options,
+    headers: bool,
+) -> csv::Result<()> {
+    let mut output = self::writer(writer, headers);
+    let (scale, held_scale) = options.output_scales();
+    let mut rows = client_rows(exchange, scale, held_scale, options.with_net).peekable();
+
+    // serializing writes the header along with the first row, so without any
+    // rows it has to be written by hand for the output to still be valid
+    if rows.peek().is_none() && headers {
+        if let Some(columns) = &options.columns {
+            output.write_record(columns)?;
+        } else {
+            let net: &[&str] = if options.with_net { &["net"] } else { &[] };
+            output.write_record(CLIENT_HEADER.iter().chain(net))?;
+        }
+    }
+
+    for row in rows {
+        write_row(&mut output, row, options)?;
+    }
+
+    output.flush()?;
+    Ok(())
+}
+
+/// How the rows of the client summary are created.
+#[derive(Clone, Copy)]
+struct RowFormat {
+    /// Whether rows have the asset column, which has to be present on every
+    /// row or none of them.
+    with_assets: bool,
+    scale: u8,
+    held_scale: u8,
+    with_net: bool,
+}
+
+impl RowFormat {
+    /// Creates the format for the exchange's clients, with held amounts in
+    /// `held_scale` and the others in `scale`. Rows only have the net deposits
+    /// if `with_net` is set.
+    fn new(exchange: &Exchange, scale: u8, held_scale: u8, with_net: bool) -> RowFormat {
+        let with_assets = exchange
+            .clients()
+            .any(|(_, client)| client.balances.keys().any(|asset| asset != DEFAULT_ASSET));
+
+        RowFormat {
+            with_assets,
+            scale,
+            held_scale,
+            with_net,
+        }
+    }
+
+    /// Creates a row for each of the client's assets, ordered by asset.
+    fn rows<'a>(
+        self,
+        exchange: &Exchange,
+        id: &'a ClientId,
+        client: &'a Client,
+    ) -> Vec<ClientDTO<'a>> {
+        // a client only has a few assets, so sorting them is cheap
+        let mut balances = client.balances.iter().collect::<Vec<_>>();
+        balances.sort_unstable_by_key(|&(asset, _)| asset);
+
+        balances
+            .into_iter()
+            .map(|(asset, balances)| {
+                let asset = if self.with_assets {
+                    Some(asset.as_str())
+                } else {
+                    None
+                };
+                let mut row = ClientDTO::new(
+                    id,
+                    asset,
+                    balances,
+                    client.locked,
+                    self.scale,
+                    self.held_scale,
+                );
+                if self.with_net {
+                    let net = exchange
+                        .flows(*id, row.asset.unwrap_or(DEFAULT_ASSET))
+                        .net();
+                    row.net = Some(AmountDTO(net, self.scale));
+                }
+                row
+            })
+            .collect()
     }
+}
+
+/// Lazily creates a row for every client and asset, in the exchange's order of
+/// clients and then by asset. See `RowFormat::new` for the arguments.
+fn client_rows(
+    exchange: &Exchange,
+    scale: u8,
+    held_scale: u8,
+    with_net: bool,
+) -> impl Iterator<Item = ClientDTO<'_>> + '_ {
+    let format = RowFormat::new(exchange, scale, held_scale, with_net);
+    exchange
+        .clients()
+        .flat_map(move |(id, client)| format.rows(exchange, id, client))
+}
 
-    let mut output = Writer::from_writer(io::stdout());
+/// Writes each client's rows of the summary to its own `client_<id>.csv` file
+/// in the directory, creating the directory if needed. Files for the same
+/// clients are replaced.
+fn write_client_files(
+    exchange: &Exchange,
+    dir: &Path,
+    options: &Options,
+) -> Result<(), Box<dyn Error>> {
+    std::fs::create_dir_all(dir)
+        .map_err(|err| format!("could not create output directory: {}", err))?;
 
+    let (scale, held_scale) = options.output_scales();
+    let format = RowFormat::new(exchange, scale, held_scale, options.with_net);
     for (id, client) in exchange.clients() {
-        let dto = ClientDTO::new(id, client);
-        output.serialize(dto).expect("failed to write row");
+        let rows = format.rows(exchange, id, client);
+        if rows.is_empty() {
+            continue;
+        }
+
+        let path = dir.join(format!("client_{}.csv", id));
+        let file = File::create(&path)
+            .map_err(|err| format!("could not create {}: {}", path.display(), err))?;
+
+        let mut output = writer(file, true);
+        for row in rows {
+            write_row(&mut output, row, options)?;
+        }
+        output.flush()?;
+    }
+
+    Ok(())
+}
+
+/// Writes a row of the client summary, with only the options' columns if they
+/// choose them.
+fn write_row<W: io::Write>(
+    output: &mut Writer<W>,
+    row: ClientDTO<'_>,
+    options: &Options,
+) -> csv::Result<()> {
+    match &options.columns {
+        Some(columns) => output.serialize(ColumnsDTO { row, columns }),
+        None => output.serialize(row),
+    }
+}
+
+/// Writes the client summary as a table for reading at a terminal, with every
+/// column right-aligned. Amounts have the options' output scales, and the
+/// columns are the chosen ones if any. The header is only written if `headers`
+/// is set. The rows are created twice,
+/// once to measure the columns and once to write them, so the table is never
+/// held in memory.
+fn write_clients_pretty<W: io::Write>(
+    exchange: &Exchange,
+    mut writer: W,
+    options: &Options,
+    headers: bool,
+) -> io::Result<()> {
+    let (scale, held_scale) = options.output_scales();
+    let with_assets = RowFormat::new(exchange, scale, held_scale, options.with_net).with_assets;
+
+    let mut all_columns = CLIENT_HEADER.to_vec();
+    if with_assets {
+        all_columns.insert(1, "asset");
+    }
+    if options.with_net {
+        all_columns.push("net");
+    }
+    let columns = options.columns.as_deref().unwrap_or(&all_columns);
+
+    let header = if headers {
+        Some(columns.iter().map(|&column| column.to_owned()).collect())
+    } else {
+        None
+    };
+    let cells = |row: ClientDTO<'_>| {
+        columns
+            .iter()
+            .map(|column| row.cell(column))
+            .collect::<Vec<_>>()
+    };
+    let table = || {
+        header
+            .clone()
+            .into_iter()
+            .chain(client_rows(exchange, scale, held_scale, options.with_net).map(cells))
+    };
+
+    let mut widths = Vec::new();
+    for cells in table() {
+        widths.resize(cells.len(), 0);
+        for (width, cell) in widths.iter_mut().zip(&cells) {
+            *width = cell.len().max(*width);
+        }
+    }
+
+    for cells in table() {
+        let line = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, &width)| format!("{:>1$}", cell, width))
+            .collect::<Vec<_>>();
+        writeln!(writer, "{}", line.join("  "))?;
+    }
+
+    writer.flush()
+}
+
+/// Writes the final state of every transaction in the order they were
+/// processed.
+fn write_transactions<W: io::Write>(exchange: &Exchange, writer: W) -> csv::Result<()> {
+    let mut output = self::writer(writer, true);
+
+    for (tx, status) in exchange.transaction_states() {
+        output.serialize(TransactionStateDTO::new(tx, status))?;
+    }
+
+    output.flush()?;
+    Ok(())
+}
+
+/// How many transactions are processed between progress messages.
+const PROGRESS_INTERVAL: u64 = 1_000_000;
+
+/// Reads all transactions from the input and processes them. Rows that can't
+/// be read, like a final row that was cut off, are reported to `log` and
+/// skipped so the rest of the input still counts. Reading stops early once the
+/// options' limit is reached. Transactions for clients the options exclude are
+/// dropped, and how many were is reported to `log`.
+///
+/// If the options ask for a self-check, processing stops with a
+/// `SelfCheckFailed` error as soon as the balances don't match the history.
+/// When validating, transactions the exchange rejects are reported to `log`
+/// too.
+fn process_reader<R: io::Read, W: io::Write>(
+    exchange: &mut Exchange,
+    input: R,
+    options: &Options,
+    mut log: W,
+) -> Result<Processed, Box<dyn Error>> {
+    let mut transactions = input::transactions_with_options(input, options.parse.clone());
+    let mut count = 0;
+    let mut rejected = 0;
+    let mut rows = 0;
+    let mut excluded = 0;
+    let mut latest = None;
+
+    // rows only ever go up by one, so this stops exactly at the limit
+    while Some(rows) != options.limit {
+        let transaction = match transactions.next() {
+            Some(transaction) => transaction,
+            None => break,
+        };
+
+        match transaction {
+            Ok(transaction) => {
+                rows += 1;
+                if let Some(clients) = &options.only_clients {
+                    if !clients.contains(&transaction.client()) {
+                        excluded += 1;
+                        continue;
+                    }
+                }
+
+                // stale disputes are resolved once a row shows their time is
+                // up, which can only change when time moves on
+                let timestamp = transactions.row_timestamp();
+                if let Some(time) = timestamp {
+                    if options.dispute_window.is_some() && latest < timestamp {
+                        latest = timestamp;
+                        for tx in exchange.expire_disputes(time) {
+                            if options.trace {
+                                writeln!(log, "tx {} dispute expired", tx)?;
+                            }
+                        }
+                    }
+                }
+
+                count += 1;
+                let tx = transaction.tx_id();
+                let traced = if options.trace {
+                    Some(trace_prefix(&transaction))
+                } else {
+                    None
+                };
+                let result = match timestamp {
+                    Some(timestamp) => exchange.process_at(transaction, timestamp),
+                    None => exchange.process(transaction),
+                };
+                if let Some(prefix) = traced {
+                    writeln!(log, "{} {}", prefix, trace_effect(&result))?;
+                }
+                if result.is_err() {
+                    rejected += 1;
+                }
+                match &result {
+                    Err(err) if options.errors_json => {
+                        let error = format!("{:?}", err);
+                        writeln!(log, "{}", error_json(transactions.line(), Some(tx), &error))?;
+                    }
+                    Err(err) if options.command == Command::Validate => {
+                        writeln!(
+                            log,
+                            "rejected line {}: tx {} failed: {:?}",
+                            transactions.line(),
+                            tx,
+                            err
+                        )?;
+                    }
+                    // other than when tracing, validating, or writing them as
+                    // JSON, errors are just swallowed for now, in the long
+                    // term they should be logged somewhere.
+                    _ => {}
+                }
+
+                if let Some(interval) = options.progress {
+                    if count % interval == 0 {
+                        writeln!(log, "processed {} transactions", group_digits(count))?;
+                    }
+                }
+
+                if let Some(interval) = options.self_check {
+                    if count % interval == 0 {
+                        let clients = exchange.reconcile();
+                        if !clients.is_empty() {
+                            return Err(Box::new(SelfCheckFailed { count, clients }));
+                        }
+                    }
+                }
+            }
+            Err(ParseError::Csv(err)) if err.is_io_error() => return Err(err.into()),
+            Err(err) if options.errors_json => {
+                rejected += 1;
+                let error = err.to_string();
+                writeln!(log, "{}", error_json(transactions.line(), None, &error))?;
+                if options.limit_counts_errors {
+                    rows += 1;
+                }
+            }
+            Err(err) => {
+                rejected += 1;
+                writeln!(log, "skipping line {}: {}", transactions.line(), err)?;
+                if options.limit_counts_errors {
+                    rows += 1;
+                }
+            }
+        }
+    }
+
+    if excluded > 0 {
+        writeln!(
+            log,
+            "skipped {} transactions for excluded clients",
+            excluded
+        )?;
+    }
+
+    Ok(Processed { count, rejected })
+}
+
+/// What `process_reader` got through.
+#[derive(Debug, PartialEq)]
+struct Processed {
+    /// How many transactions were processed, whether they succeeded or not.
+    count: u64,
+
+    /// How many rows couldn't be parsed or were rejected by the exchange.
+    rejected: u64,
+}
+
+/// Describes a rejected row as a JSON object for `--errors-json`, like
+/// `{"line":42,"tx":5,"error":"InsufficientFunds"}`. The transaction ID is
+/// null for rows that couldn't be parsed.
+fn error_json(line: u64, tx: Option<TransactionId>, error: &str) -> String {
+    let tx = tx.map_or_else(|| String::from("null"), |tx| tx.to_string());
+
+    format!(
+        r#"{{"line":{},"tx":{},"error":{}}}"#,
+        line,
+        tx,
+        json_string(error)
+    )
+}
+
+/// Quotes the text as a JSON string, escaping the characters JSON requires.
+fn json_string(text: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+
+    quoted
+}
+
+/// The balances of some clients stopped matching what their history says they
+/// should be, so they can no longer be trusted.
+#[derive(Debug)]
+struct SelfCheckFailed {
+    /// How many transactions had been processed.
+    count: u64,
+    clients: Vec<ClientId>,
+}
+
+impl fmt::Display for SelfCheckFailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let clients = self
+            .clients
+            .iter()
+            .map(ClientId::to_string)
+            .collect::<Vec<_>>();
+
+        write!(
+            f,
+            "self-check failed after {} transactions, balances don't match the history for clients {}",
+            group_digits(self.count),
+            clients.join(", ")
+        )
+    }
+}
+
+impl Error for SelfCheckFailed {}
+
+/// Computes a SHA-256 checksum of the client summary. The rows are sorted
+/// first so the checksum doesn't depend on the order clients are stored in.
+fn checksum(exchange: &Exchange, options: &Options) -> csv::Result<String> {
+    let mut output = Vec::new();
+    write_clients(exchange, &mut output, options, true)?;
+
+    let mut rows = output
+        .split(|&byte| byte == b'\n')
+        .filter(|row| !row.is_empty())
+        .collect::<Vec<_>>();
+
+    // the header stays first
+    if let Some((_, rows)) = rows.split_first_mut() {
+        rows.sort_unstable();
+    }
+
+    let mut hasher = Sha256::new();
+    for row in rows {
+        hasher.update(row);
+        hasher.update(b"\n");
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Describes the transaction for a trace line, like `tx 5 deposit client 1
+/// +1.0000`.
+fn trace_prefix(transaction: &Transaction) -> String {
+    let (kind, change) = match transaction {
+        Transaction::Deposit(.., amount) => (
+            "deposit",
+            format!(" +{}", format_amount(*amount, DEFAULT_SCALE)),
+        ),
+        Transaction::Withdrawal(.., amount) => (
+            "withdrawal",
+            format!(" -{}", format_amount(*amount, DEFAULT_SCALE)),
+        ),
+        Transaction::Transfer(_, to, .., amount) => (
+            "transfer",
+            format!(
+                " -{} to client {}",
+                format_amount(*amount, DEFAULT_SCALE),
+                to
+            ),
+        ),
+        Transaction::Dispute(..) => ("dispute", String::new()),
+        Transaction::Resolve(..) => ("resolve", String::new()),
+        Transaction::Chargeback(..) => ("chargeback", String::new()),
+        Transaction::SessionBoundary(..) => ("session", String::new()),
+        Transaction::Freeze(..) => ("freeze", String::new()),
+        Transaction::Unfreeze(..) => ("unfreeze", String::new()),
+    };
+
+    format!(
+        "tx {} {} client {}{}",
+        transaction.tx_id(),
+        kind,
+        transaction.client(),
+        change
+    )
+}
+
+/// Describes what a transaction did to the client's balances for a trace line,
+/// like `-> available 1.0000`.
+fn trace_effect(result: &Result<ProcessOutcome, ExchangeError>) -> String {
+    match result {
+        Ok(ProcessOutcome::Deposited { new_available })
+        | Ok(ProcessOutcome::Withdrawn { new_available })
+        | Ok(ProcessOutcome::Transferred { new_available }) => {
+            format!(
+                "-> available {}",
+                format_amount(*new_available, DEFAULT_SCALE)
+            )
+        }
+        Ok(ProcessOutcome::Held { amount }) => {
+            format!("-> held {}", format_amount(*amount, DEFAULT_SCALE))
+        }
+        Ok(ProcessOutcome::Released { amount }) => {
+            format!("-> released {}", format_amount(*amount, DEFAULT_SCALE))
+        }
+        Ok(ProcessOutcome::Locked) => String::from("-> locked"),
+        Ok(ProcessOutcome::SessionStarted) => String::from("-> new session"),
+        Ok(ProcessOutcome::Frozen) => String::from("-> frozen"),
+        Ok(ProcessOutcome::Unfrozen) => String::from("-> unfrozen"),
+        Err(err) => format!("failed: {:?}", err),
+    }
+}
+
+/// Describes the run for the comment line `--with-metadata` starts the summary
+/// with, like `# generated 2024-01-01 from input.csv, 1234 rows`.
+fn metadata_line(input_filename: &str, rows: u64, now: SystemTime) -> String {
+    format!(
+        "# generated {} from {}, {} rows",
+        utc_date(now),
+        input_filename,
+        rows
+    )
+}
+
+/// Writes the metadata comment line, if there is one.
+fn write_metadata<W: io::Write>(mut writer: W, metadata: Option<&str>) -> io::Result<()> {
+    match metadata {
+        Some(line) => writeln!(writer, "{}", line),
+        None => Ok(()),
+    }
+}
+
+/// Formats the UTC date of the time, like `2024-01-01`.
+fn utc_date(time: SystemTime) -> String {
+    let days = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs() / 86_400);
+
+    // counting from 0000-03-01 puts leap days at the end of the year, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let (year, month) = if month < 10 {
+        (era * 400 + year_of_era, month + 3)
+    } else {
+        (era * 400 + year_of_era + 1, month - 9)
+    };
+
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Formats a count with commas between groups of digits, like `1,234,567`.
+fn group_digits(count: u64) -> String {
+    let digits = count.to_string();
+    let mut grouped = String::new();
+
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && i % 3 == digits.len() % 3 {
+            grouped.push(',');
+        }
+        grouped.push(digit);
+    }
+
+    grouped
+}
+
+/// Formats a summary of how long processing took, like `processed 1,234,567
+/// transactions in 2.1s (588k tx/s)`.
+fn stats_line(count: u64, elapsed: Duration) -> String {
+    // avoid dividing by zero for tiny inputs
+    let seconds = elapsed.as_secs_f64().max(1e-9);
+    let rate = count as f64 / seconds;
+    let rate = if rate >= 1e6 {
+        format!("{:.1}M", rate / 1e6)
+    } else if rate >= 1e3 {
+        format!("{:.0}k", rate / 1e3)
+    } else {
+        format!("{:.0}", rate)
+    };
+
+    format!(
+        "processed {} transactions in {:.1}s ({} tx/s)",
+        group_digits(count),
+        elapsed.as_secs_f64(),
+        rate
+    )
+}
+
+/// Creates an empty exchange following the options. Clients are kept ordered
+/// so the summary can be written in ID order without sorting them.
+fn exchange(options: &Options) -> Exchange {
+    let mut builder = ExchangeBuilder::new()
+        .ordered_clients(true)
+        .record_transactions(!options.no_disputes)
+        .forbid_client_zero(options.forbid_client_zero);
+    if let Some(window) = options.dispute_window {
+        builder = builder.dispute_window(window);
+    }
+
+    builder.build()
+}
+
+/// Runs the program, writing the command's result to `output` and anything
+/// else to `log`.
+fn run<W: io::Write, L: io::Write>(
+    options: &Options,
+    output: W,
+    mut log: L,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(address) = &options.listen {
+        return listen(address, options, output, log);
+    }
+
+    let input_file = File::open(&options.input_filename)
+        .map_err(|err| format!("could not open file: {}", err))?;
+
+    let start = Instant::now();
+    let mut exchange = exchange(options);
+    let processed = process_reader(&mut exchange, input_file, options, &mut log)?;
+    let elapsed = start.elapsed();
+
+    match &options.command {
+        Command::Process => write_results(&exchange, processed.count, options, output, &mut log)?,
+        Command::Validate => {
+            writeln!(
+                log,
+                "validated {} transactions, {} rows rejected",
+                group_digits(processed.count),
+                group_digits(processed.rejected)
+            )?;
+            if processed.rejected > 0 {
+                return Err("the input has rejected rows".into());
+            }
+        }
+        Command::Stats => write_stats(&exchange, processed.count, elapsed, output)?,
+        Command::Snapshot(path) => save_snapshot(&exchange, path)?,
+    }
+
+    if options.stats {
+        writeln!(log, "{}", stats_line(processed.count, elapsed))?;
+    }
+
+    if options.forbid_negative {
+        check_not_negative(&exchange)?;
+    }
+
+    Ok(())
+}
+
+/// Writes what `process` produces once every transaction is processed: the
+/// client summary to `output` or wherever the options say, and any checksum
+/// or transaction dump they ask for.
+fn write_results<W: io::Write, L: io::Write>(
+    exchange: &Exchange,
+    count: u64,
+    options: &Options,
+    output: W,
+    mut log: L,
+) -> Result<(), Box<dyn Error>> {
+    let metadata = if options.with_metadata {
+        Some(metadata_line(
+            &options.input_filename,
+            count,
+            SystemTime::now(),
+        ))
+    } else {
+        None
+    };
+
+    match (&options.output, &options.output_dir) {
+        (_, Some(dir)) => write_client_files(exchange, Path::new(dir), options)?,
+        (Some(path), None) => {
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create(true)
+                .append(options.append)
+                .truncate(!options.append)
+                .open(path)
+                .map_err(|err| format!("could not open output file: {}", err))?;
+            // appended rows go under the header that's already there
+            let headers = !options.append || file.metadata()?.len() == 0;
+            if headers {
+                write_metadata(&mut file, metadata.as_deref())?;
+            }
+            write_summary(exchange, file, options, headers)?;
+        }
+        (None, None) => {
+            let mut output = output;
+            write_metadata(&mut output, metadata.as_deref())?;
+            write_summary(exchange, output, options, true)?;
+        }
+    }
+
+    if options.print_checksum {
+        writeln!(log, "sha256: {}", checksum(exchange, options)?)?;
+    }
+
+    if let Some(path) = &options.dump_transactions {
+        let file = File::create(path).map_err(|err| format!("could not create file: {}", err))?;
+        write_transactions(exchange, file)?;
+    }
+
+    Ok(())
+}
+
+/// Writes statistics about the processed transactions for `stats`: the
+/// processing rate, how many clients there are, and how many transactions
+/// ended up in each state.
+fn write_stats<W: io::Write>(
+    exchange: &Exchange,
+    count: u64,
+    elapsed: Duration,
+    mut output: W,
+) -> io::Result<()> {
+    let counts = exchange.state_counts();
+
+    writeln!(output, "{}", stats_line(count, elapsed))?;
+    writeln!(
+        output,
+        "clients: {}",
+        group_digits(exchange.clients().count() as u64)
+    )?;
+    writeln!(
+        output,
+        "transactions: {} completed, {} disputed, {} resolved, {} charged back",
+        group_digits(counts.completed as u64),
+        group_digits(counts.disputed as u64),
+        group_digits(counts.resolved as u64),
+        group_digits(counts.charged_back as u64)
+    )?;
+
+    output.flush()
+}
+
+/// Saves the exchange's state to the file for `snapshot`.
+#[cfg(feature = "bincode")]
+fn save_snapshot(exchange: &Exchange, path: &str) -> Result<(), Box<dyn Error>> {
+    std::fs::write(path, exchange.snapshot_bin())
+        .map_err(|err| format!("could not write snapshot: {}", err))?;
+
+    Ok(())
+}
+
+/// Snapshots need the `bincode` feature, so without it `snapshot` always fails.
+#[cfg(not(feature = "bincode"))]
+fn save_snapshot(_exchange: &Exchange, _path: &str) -> Result<(), Box<dyn Error>> {
+    Err("'snapshot' requires the bincode feature".into())
+}
+
+/// Writes the client summary as a table if the options ask for it, or as CSV
+/// otherwise.
+fn write_summary<W: io::Write>(
+    exchange: &Exchange,
+    writer: W,
+    options: &Options,
+    headers: bool,
+) -> Result<(), Box<dyn Error>> {
+    if options.pretty {
+        write_clients_pretty(exchange, writer, options, headers)?;
+    } else {
+        write_clients(exchange, writer, options, headers)?;
+    }
+
+    Ok(())
+}
+
+/// Fails, listing the offending clients, if any client has negative available
+/// funds in any asset.
+fn check_not_negative(exchange: &Exchange) -> Result<(), String> {
+    let mut negative = exchange
+        .clients()
+        .filter(|(_, client)| {
+            client
+                .balances
+                .values()
+                .any(|balances| balances.funds_available < Money::zero())
+        })
+        .map(|(id, _)| *id)
+        .collect::<Vec<_>>();
+    if negative.is_empty() {
+        return Ok(());
+    }
+
+    negative.sort_unstable();
+    let negative = negative.iter().map(ClientId::to_string).collect::<Vec<_>>();
+    Err(format!(
+        "clients with negative available funds: {}",
+        negative.join(", ")
+    ))
+}
+
+/// Accepts connections on the address until the program is stopped, processing
+/// the CSV sent on each one, header included, into a single exchange. Once the
+/// sender closes its side, the current summary of every client is sent back.
+/// A failed connection is reported to `log` and doesn't stop the others.
+///
+/// With the `service` feature, Ctrl-C stops it gracefully: the connection being
+/// served is finished and the final summary of every client is written to
+/// `output` before exiting, so it isn't cut off.
+fn listen<W: io::Write, L: io::Write>(
+    address: &str,
+    options: &Options,
+    output: W,
+    log: L,
+) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::bind(address)
+        .map_err(|err| format!("could not listen on {}: {}", address, err))?;
+    let shutdown = Arc::new(AtomicBool::new(false));
+
+    #[cfg(feature = "service")]
+    {
+        let shutdown = Arc::clone(&shutdown);
+        let address = listener.local_addr()?;
+        ctrlc::set_handler(move || request_shutdown(&shutdown, address))
+            .map_err(|err| format!("could not handle Ctrl-C: {}", err))?;
+    }
+
+    serve_until_shutdown(&listener, &shutdown, options, output, log)
+}
+
+/// Serves the connections on the listener into a single exchange until a
+/// shutdown is requested, then writes the final client summary to `output`.
+fn serve_until_shutdown<W: io::Write, L: io::Write>(
+    listener: &TcpListener,
+    shutdown: &AtomicBool,
+    options: &Options,
+    output: W,
+    mut log: L,
+) -> Result<(), Box<dyn Error>> {
+    let mut exchange = exchange(options);
+
+    for stream in listener.incoming() {
+        // the connection that woke the listener up isn't served
+        if shutdown.load(Ordering::SeqCst) {
+            break;
+        }
+
+        let result = stream
+            .map_err(Box::<dyn Error>::from)
+            .and_then(|stream| serve(&mut exchange, &stream, options, &mut log));
+        match result {
+            // the exchange can't be trusted with any more connections
+            Err(err) if err.is::<SelfCheckFailed>() => return Err(err),
+            Err(err) => writeln!(log, "connection failed: {}", err)?,
+            Ok(()) => {}
+        }
+    }
+
+    write_summary(&exchange, output, options, true)
+}
+
+/// Asks `serve_until_shutdown` to stop, connecting to the address it listens on
+/// to wake it up in case it's waiting for a connection.
+#[cfg(any(feature = "service", test))]
+fn request_shutdown(shutdown: &AtomicBool, mut address: SocketAddr) {
+    shutdown.store(true, Ordering::SeqCst);
+
+    // a listener on every interface can be reached locally
+    if address.ip().is_unspecified() {
+        address.set_ip(Ipv4Addr::LOCALHOST.into());
+    }
+    // failing to connect means nothing is listening anymore anyway
+    let _ = TcpStream::connect(address);
+}
+
+/// Processes the transactions sent on the connection and replies with the
+/// client summary.
+fn serve<L: io::Write>(
+    exchange: &mut Exchange,
+    stream: &TcpStream,
+    options: &Options,
+    log: L,
+) -> Result<(), Box<dyn Error>> {
+    process_reader(exchange, stream, options, log)?;
+    write_clients(exchange, stream, options, true)?;
+    Ok(())
+}
+
+/// Whether the error came from writing to a closed pipe, like when the output
+/// is piped into `head`.
+fn is_broken_pipe(err: &(dyn Error + 'static)) -> bool {
+    let err = match err.downcast_ref::<csv::Error>() {
+        Some(err) => match err.kind() {
+            csv::ErrorKind::Io(err) => Some(err),
+            _ => None,
+        },
+        None => err.downcast_ref::<io::Error>(),
+    };
+
+    matches!(err, Some(err) if err.kind() == io::ErrorKind::BrokenPipe)
+}
+
+fn main() {
+    let options = match Options::parse(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            process::exit(1);
+        }
+    };
+
+    if let Err(err) = run(&options, io::stdout(), io::stderr()) {
+        // whoever was reading the output stopped, which isn't a failure
+        if is_broken_pipe(&*err) {
+            return;
+        }
+
+        eprintln!("error: {}", err);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_coding_test::transaction::Transaction;
+    use std::io::{Read, Write};
+
+    /// The amount as the `Money` the program was built with.
+    fn money(amount: &str) -> Money {
+        amount.parse().unwrap()
+    }
+
+    #[test]
+    fn options_parse_dump_transactions_path() {
+        let args = vec!["input.csv", "--dump-transactions", "states.csv"];
+        let options = Options::parse(args.into_iter().map(String::from)).unwrap();
+
+        assert_eq!(options.input_filename, "input.csv");
+        assert_eq!(options.dump_transactions.as_deref(), Some("states.csv"));
+    }
+
+    #[test]
+    fn options_parse_commands() {
+        let parse = |args: &[&str]| Options::parse(args.iter().map(|&arg| String::from(arg)));
+
+        assert_eq!(parse(&["input.csv"]).unwrap().command, Command::Process);
+        assert_eq!(
+            parse(&["process", "input.csv"]).unwrap().command,
+            Command::Process
+        );
+        assert_eq!(
+            parse(&["validate", "input.csv"]).unwrap().command,
+            Command::Validate
+        );
+        assert_eq!(
+            parse(&["stats", "input.csv"]).unwrap().command,
+            Command::Stats
+        );
+        assert_eq!(
+            parse(&["snapshot", "input.csv", "--to", "state.bin"])
+                .unwrap()
+                .command,
+            Command::Snapshot(String::from("state.bin"))
+        );
+
+        assert_eq!(
+            parse(&["snapshot", "input.csv"]).err().as_deref(),
+            Some("'snapshot' requires '--to'")
+        );
+        assert_eq!(
+            parse(&["input.csv", "--to", "state.bin"]).err().as_deref(),
+            Some("'--to' is only for 'snapshot'")
+        );
+        assert_eq!(
+            parse(&["validate", "input.csv", "--pretty"])
+                .err()
+                .as_deref(),
+            Some("'--pretty' is only for 'process'")
+        );
+    }
+
+    #[test]
+    fn run_validate_reports_rejected_rows_without_balances() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,1.0\n\
+                     withdrawal,1,2,5.0\n\
+                     deposit,1,3\n";
+        let args = vec![String::from("validate"), temp_file("validate.csv", input)];
+        let options = Options::parse(args.into_iter()).unwrap();
+        let mut output = Vec::new();
+        let mut log = Vec::new();
+
+        let err = run(&options, &mut output, &mut log).unwrap_err();
+
+        assert_eq!(err.to_string(), "the input has rejected rows");
+        assert!(output.is_empty());
+        let log = String::from_utf8(log).unwrap();
+        assert_eq!(
+            log,
+            "rejected line 3: tx 2 failed: InsufficientFunds\n\
+             skipping line 4: missing 'amount' field\n\
+             validated 2 transactions, 2 rows rejected\n"
+        );
+    }
+
+    #[test]
+    fn run_stats_writes_statistics_instead_of_balances() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,1.0\n\
+                     deposit,2,2,2.0\n\
+                     dispute,2,2,\n";
+        let args = vec![String::from("stats"), temp_file("stats-command.csv", input)];
+        let options = Options::parse(args.into_iter()).unwrap();
+        let mut output = Vec::new();
+
+        run(&options, &mut output, io::sink()).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        let lines = output.lines().collect::<Vec<_>>();
+        assert!(lines[0].starts_with("processed 3 transactions in "));
+        assert_eq!(
+            &lines[1..],
+            &[
+                "clients: 2",
+                "transactions: 1 completed, 1 disputed, 0 resolved, 0 charged back"
+            ]
+        );
+    }
+
+    #[test]
+    fn process_reader_writes_errors_as_json_when_set() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,1.0\n\
+                     withdrawal,1,2,5.0\n\
+                     refund,1,3,1.0\n\
+                     dispute,1,9\n";
+        let options = Options {
+            errors_json: true,
+            ..Options::default()
+        };
+        let mut log = Vec::new();
+
+        process_reader(&mut Exchange::new(), input.as_bytes(), &options, &mut log).unwrap();
+
+        let log = String::from_utf8(log).unwrap();
+        assert_eq!(
+            log.lines().collect::<Vec<_>>(),
+            vec![
+                r#"{"line":3,"tx":2,"error":"InsufficientFunds"}"#,
+                r#"{"line":4,"tx":null,"error":"unknown transaction type 'refund'"}"#,
+                r#"{"line":5,"tx":9,"error":"TransactionNotFound"}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn json_string_escapes_quotes_backslashes_and_control_characters() {
+        assert_eq!(json_string("plain"), r#""plain""#);
+        assert_eq!(json_string("a \"b\" \\ c\n"), r#""a \"b\" \\ c\u000a""#);
+    }
+
+    #[test]
+    fn process_reader_skips_truncated_final_row() {
+        let input = "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,2.0\ndeposit,1,3";
+        let mut exchange = Exchange::new();
+        let mut log = Vec::new();
+
+        process_reader(
+            &mut exchange,
+            input.as_bytes(),
+            &Options::default(),
+            &mut log,
+        )
+        .unwrap();
+
+        let (_, client) = exchange.clients().next().unwrap();
+        assert_eq!(
+            client.balance(DEFAULT_ASSET).unwrap().funds_available,
+            money("3.0")
+        );
+
+        let log = String::from_utf8(log).unwrap();
+        assert_eq!(log, "skipping line 4: missing 'amount' field\n");
+    }
+
+    #[test]
+    fn checksum_is_the_same_for_the_same_input() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,1.0\n\
+                     deposit,2,2,2.0\n\
+                     deposit,3,3,3.0\n\
+                     withdrawal,1,4,0.5\n\
+                     deposit,4,5,4.0\n\
+                     dispute,2,2\n";
+
+        let run = || {
+            let mut exchange = Exchange::new();
+            process_reader(
+                &mut exchange,
+                input.as_bytes(),
+                &Options::default(),
+                io::sink(),
+            )
+            .unwrap();
+            checksum(&exchange, &Options::default()).unwrap()
+        };
+
+        assert_eq!(run(), run());
+    }
+
+    /// Writes the contents to a file in the temp directory, unique to the test.
+    fn temp_file(name: &str, contents: &str) -> String {
+        let path = env::temp_dir().join(format!("{}-{}", process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn stats_line_formats_count_duration_and_rate() {
+        let line = stats_line(1_234_567, Duration::from_millis(2100));
+
+        assert_eq!(line, "processed 1,234,567 transactions in 2.1s (588k tx/s)");
+    }
+
+    #[test]
+    fn run_prints_stats_when_set() {
+        let input = "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,2.0\n";
+        let options = Options {
+            input_filename: temp_file("stats.csv", input),
+            stats: true,
+            ..Options::default()
+        };
+        let mut log = Vec::new();
+
+        run(&options, io::sink(), &mut log).unwrap();
+
+        let log = String::from_utf8(log).unwrap();
+        assert!(log.starts_with("processed 2 transactions in "));
+        assert!(log.ends_with(" tx/s)\n"));
+    }
+
+    #[test]
+    fn run_doesnt_print_stats_by_default() {
+        let input = "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,2,2.0\n";
+        let options = Options {
+            input_filename: temp_file("no-stats.csv", input),
+            ..Options::default()
+        };
+        let mut log = Vec::new();
+
+        run(&options, io::sink(), &mut log).unwrap();
+
+        assert!(log.is_empty());
+    }
+
+    #[test]
+    fn write_transactions_includes_disputed_state() {
+        let mut exchange = Exchange::new();
+        exchange
+            .process(Transaction::Deposit(
+                1,
+                1,
+                DEFAULT_ASSET.into(),
+                money("1.0"),
+            ))
+            .unwrap();
+        exchange
+            .process(Transaction::Deposit(
+                1,
+                2,
+                DEFAULT_ASSET.into(),
+                money("2.0"),
+            ))
+            .unwrap();
+        exchange.process(Transaction::Dispute(1, 2)).unwrap();
+
+        let mut output = Vec::new();
+        write_transactions(&exchange, &mut output).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(output, "tx,state\n1,completed\n2,disputed\n");
+    }
+
+    #[test]
+    fn write_clients_adds_net_deposits_with_net() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,5.0\n\
+                     deposit,1,2,2.5\n\
+                     withdrawal,1,3,1.5\n\
+                     deposit,2,4,3.0\n\
+                     dispute,1,2\n\
+                     withdrawal,2,5,4.0\n";
+        let options = Options {
+            with_net: true,
+            ..Options::default()
+        };
+        let mut exchange = Exchange::new_ordered();
+        process_reader(&mut exchange, input.as_bytes(), &options, io::sink()).unwrap();
+
+        let mut output = Vec::new();
+        write_clients(&exchange, &mut output, &options, true).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            "client,available,held,total,locked,net\n\
+             1,3.5000,2.5000,6.0000,false,6.0000\n\
+             2,3.0000,0.0000,3.0000,false,3.0000\n"
+        );
+    }
+
+    #[test]
+    fn process_reader_aborts_when_self_check_fails() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,1.0\n\
+                     deposit,2,2,2.0\n\
+                     deposit,3,3,3.0\n\
+                     deposit,4,4,4.0\n";
+        let options = Options {
+            self_check: Some(2),
+            ..Options::default()
+        };
+        let mut exchange = Exchange::new();
+        process_reader(&mut exchange, input.as_bytes(), &options, io::sink()).unwrap();
+
+        // without a history to replay, every balance looks corrupted
+        let mut corrupted = ExchangeBuilder::new().record_transactions(false).build();
+        let err =
+            process_reader(&mut corrupted, input.as_bytes(), &options, io::sink()).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "self-check failed after 2 transactions, balances don't match the history for clients 1, 2"
+        );
+        assert_eq!(corrupted.clients().count(), 2);
+    }
+
+    #[test]
+    fn write_clients_writes_fixed_point_amounts() {
+        let mut exchange = Exchange::new();
+        exchange
+            .process(Transaction::Deposit(
+                1,
+                1,
+                DEFAULT_ASSET.into(),
+                money("0.1"),
+            ))
+            .unwrap();
+        exchange
+            .process(Transaction::Deposit(
+                1,
+                2,
+                DEFAULT_ASSET.into(),
+                money("0.2"),
+            ))
+            .unwrap();
+
+        let mut output = Vec::new();
+        write_clients(&exchange, &mut output, &Options::default(), true).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            "client,available,held,total,locked\n1,0.3000,0.0000,0.3000,false\n"
+        );
+    }
+
+    #[test]
+    fn write_clients_uses_lf_line_endings() {
+        let mut exchange = Exchange::new();
+        exchange
+            .process(Transaction::Deposit(
+                1,
+                1,
+                DEFAULT_ASSET.into(),
+                money("1.0"),
+            ))
+            .unwrap();
+
+        let mut output = Vec::new();
+        write_clients(&exchange, &mut output, &Options::default(), true).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            "client,available,held,total,locked\n1,1.0000,0.0000,1.0000,false\n"
+        );
+    }
+
+    #[test]
+    fn process_reader_reports_progress_at_interval() {
+        let mut input = String::from("type,client,tx,amount\n");
+        for tx in 1..=7 {
+            input.push_str(&format!("deposit,1,{},1.0\n", tx));
+        }
+        let options = Options {
+            progress: Some(3),
+            ..Options::default()
+        };
+        let mut log = Vec::new();
+
+        process_reader(&mut Exchange::new(), input.as_bytes(), &options, &mut log).unwrap();
+
+        let log = String::from_utf8(log).unwrap();
+        assert_eq!(log, "processed 3 transactions\nprocessed 6 transactions\n");
+    }
+
+    /// A writer that accepts some bytes and then fails as if the reader had
+    /// closed the pipe.
+    struct ClosedPipe {
+        remaining: usize,
+    }
+
+    impl io::Write for ClosedPipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.remaining == 0 {
+                return Err(io::Error::from(io::ErrorKind::BrokenPipe));
+            }
+
+            let written = buf.len().min(self.remaining);
+            self.remaining -= written;
+            Ok(written)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn run_reports_broken_pipe_when_output_closes() {
+        let input = "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,2,2,2.0\n";
+        let options = Options {
+            input_filename: temp_file("broken-pipe.csv", input),
+            ..Options::default()
+        };
+        // the header and the first row
+        let output = ClosedPipe { remaining: 55 };
+
+        let err = run(&options, output, io::sink()).unwrap_err();
+
+        assert!(is_broken_pipe(&*err));
+    }
+
+    #[test]
+    fn process_reader_stops_at_limit() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,1.0\n\
+                     deposit,1,2\n\
+                     deposit,1,3,2.0\n\
+                     deposit,1,4,4.0\n";
+        let run = |limit_counts_errors| {
+            let options = Options {
+                limit: Some(2),
+                limit_counts_errors,
+                ..Options::default()
+            };
+            let mut exchange = Exchange::new();
+            let count = process_reader(&mut exchange, input.as_bytes(), &options, io::sink())
+                .unwrap()
+                .count;
+            let balances = exchange.clients().next().unwrap().1.balance(DEFAULT_ASSET);
+
+            (count, balances.unwrap().funds_available)
+        };
+
+        assert_eq!(run(false), (2, money("3.0")));
+        assert_eq!(run(true), (1, money("1.0")));
+    }
+
+    #[test]
+    fn options_parse_only_clients() {
+        let args = vec!["input.csv", "--only-clients", "1, 3"];
+        let options = Options::parse(args.into_iter().map(String::from)).unwrap();
+
+        let expected = vec![1, 3].into_iter().collect::<HashSet<_>>();
+        assert_eq!(options.only_clients, Some(expected));
+    }
+
+    #[test]
+    fn process_reader_skips_excluded_clients() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,1.0\n\
+                     deposit,2,2,2.0\n\
+                     deposit,3,3,3.0\n\
+                     withdrawal,2,4,1.0\n";
+        let options = Options {
+            only_clients: Some(vec![1, 3].into_iter().collect()),
+            ..Options::default()
+        };
+        let mut exchange = Exchange::new();
+        let mut log = Vec::new();
+
+        let count = process_reader(&mut exchange, input.as_bytes(), &options, &mut log)
+            .unwrap()
+            .count;
+
+        assert_eq!(count, 2);
+        let mut clients = exchange.clients().map(|(&id, _)| id).collect::<Vec<_>>();
+        clients.sort_unstable();
+        assert_eq!(clients, vec![1, 3]);
+
+        let log = String::from_utf8(log).unwrap();
+        assert_eq!(log, "skipped 2 transactions for excluded clients\n");
+    }
+
+    #[test]
+    fn write_clients_orders_equal_balances_by_client_id() {
+        let mut exchange = Exchange::new_ordered();
+        for (tx, client) in [(1, 12), (2, 3), (3, 7), (4, 1)].iter() {
+            exchange
+                .process(Transaction::Deposit(
+                    *client,
+                    *tx,
+                    DEFAULT_ASSET.into(),
+                    money("1.0"),
+                ))
+                .unwrap();
+        }
+
+        let mut output = Vec::new();
+        write_clients(&exchange, &mut output, &Options::default(), true).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        let clients = output
+            .lines()
+            .skip(1)
+            .map(|row| row.split(',').next().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(clients, vec!["1", "3", "7", "12"]);
+    }
+
+    #[test]
+    fn write_clients_writes_chosen_columns_in_order() {
+        let mut exchange = Exchange::new_ordered();
+        exchange
+            .process(Transaction::Deposit(
+                1,
+                1,
+                DEFAULT_ASSET.into(),
+                money("2.5"),
+            ))
+            .unwrap();
+        exchange
+            .process(Transaction::Deposit(
+                2,
+                2,
+                DEFAULT_ASSET.into(),
+                money("1.0"),
+            ))
+            .unwrap();
+        exchange.process(Transaction::Dispute(2, 2)).unwrap();
+
+        let args = vec!["input.csv", "--columns", "locked,total,client,held"];
+        let options = Options::parse(args.into_iter().map(String::from)).unwrap();
+        let mut output = Vec::new();
+        write_clients(&exchange, &mut output, &options, true).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            "locked,total,client,held\nfalse,2.5000,1,0.0000\nfalse,1.0000,2,1.0000\n"
+        );
+
+        let mut output = Vec::new();
+        write_clients(&Exchange::new(), &mut output, &options, true).unwrap();
+        assert_eq!(output, b"locked,total,client,held\n");
+    }
+
+    #[test]
+    fn options_parse_rejects_unknown_columns() {
+        let args = vec!["input.csv", "--columns", "client,balance"];
+        let result = Options::parse(args.into_iter().map(String::from));
+
+        assert_eq!(result.err(), Some(String::from("unknown column 'balance'")));
+    }
+
+    #[test]
+    fn options_parse_rejects_results_flags_with_listen() {
+        for flag in ["--stats", "--forbid-negative", "--print-checksum"].iter() {
+            let args = vec!["--listen", "127.0.0.1:0", flag];
+            let result = Options::parse(args.into_iter().map(String::from));
+
+            assert_eq!(
+                result.err(),
+                Some(format!("'{}' can't be used with '--listen'", flag))
+            );
+        }
+    }
+
+    #[test]
+    fn write_clients_streams_many_clients_in_order() {
+        let mut exchange = Exchange::new_ordered();
+        for client in (1..=5000).rev() {
+            exchange
+                .process(Transaction::Deposit(
+                    client,
+                    u32::from(client),
+                    DEFAULT_ASSET.into(),
+                    money("1.0"),
+                ))
+                .unwrap();
+        }
+
+        // the rows come straight from the ordered clients
+        let mut rows = client_rows(&exchange, DEFAULT_SCALE, DEFAULT_SCALE, false);
+        assert_eq!(rows.next().map(|row| row.client), Some(1));
+        assert_eq!(rows.count(), 4999);
+
+        let mut output = Vec::new();
+        write_clients(&exchange, &mut output, &Options::default(), true).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        let clients = output
+            .lines()
+            .skip(1)
+            .map(|row| row.split(',').next().unwrap().parse::<ClientId>().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(clients, (1..=5000).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn options_parse_append_requires_output() {
+        let args = vec!["input.csv", "--append"];
+        let options = Options::parse(args.into_iter().map(String::from));
+
+        assert_eq!(
+            options.err().as_deref(),
+            Some("'--append' requires '--output'")
+        );
+    }
+
+    #[test]
+    fn run_appends_to_output_with_a_single_header() {
+        let output = env::temp_dir().join(format!("{}-appended.csv", process::id()));
+        let _ = std::fs::remove_file(&output);
+
+        for (name, input) in [
+            ("append-1.csv", "type,client,tx,amount\ndeposit,1,1,1.0\n"),
+            ("append-2.csv", "type,client,tx,amount\ndeposit,2,1,2.0\n"),
+        ]
+        .iter()
+        {
+            let options = Options {
+                input_filename: temp_file(name, input),
+                output: Some(output.to_string_lossy().into_owned()),
+                append: true,
+                ..Options::default()
+            };
+            run(&options, io::sink(), io::sink()).unwrap();
+        }
+
+        let output = std::fs::read_to_string(&output).unwrap();
+        assert_eq!(
+            output,
+            "client,available,held,total,locked\n\
+             1,1.0000,0.0000,1.0000,false\n\
+             2,2.0000,0.0000,2.0000,false\n"
+        );
+    }
+
+    #[test]
+    fn run_writes_a_file_per_client_to_output_dir() {
+        let dir = env::temp_dir().join(format!("{}-output-dir", process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,1.0\n\
+                     deposit,2,2,2.0\n\
+                     withdrawal,1,3,0.5\n\
+                     dispute,2,2\n";
+        let options = Options {
+            input_filename: temp_file("output-dir.csv", input),
+            output_dir: Some(dir.to_string_lossy().into_owned()),
+            ..Options::default()
+        };
+        let mut output = Vec::new();
+
+        run(&options, &mut output, io::sink()).unwrap();
+
+        assert!(output.is_empty());
+        assert_eq!(
+            std::fs::read_to_string(dir.join("client_1.csv")).unwrap(),
+            "client,available,held,total,locked\n1,0.5000,0.0000,0.5000,false\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join("client_2.csv")).unwrap(),
+            "client,available,held,total,locked\n2,0.0000,2.0000,2.0000,false\n"
+        );
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn run_skips_disputes_with_no_disputes() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,1,2.0\n\
+                     withdrawal,1,2,0.5\n\
+                     dispute,1,1\n\
+                     chargeback,1,1\n";
+        let options = Options {
+            input_filename: temp_file("no-disputes.csv", input),
+            no_disputes: true,
+            ..Options::default()
+        };
+        let mut output = Vec::new();
+
+        run(&options, &mut output, io::sink()).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            "client,available,held,total,locked\n1,1.5000,0.0000,1.5000,false\n"
+        );
+    }
+
+    #[test]
+    fn run_expires_disputes_by_row_timestamps() {
+        let input = "type,client,tx,amount,timestamp\n\
+                     deposit,1,1,2.0,100\n\
+                     deposit,1,2,1.0,100\n\
+                     dispute,1,1,,200\n\
+                     dispute,1,2,,250\n\
+                     deposit,1,3,0.5,260\n";
+        let options = Options {
+            input_filename: temp_file("dispute-window.csv", input),
+            dispute_window: Some(60),
+            trace: true,
+            ..Options::default()
+        };
+        let mut output = Vec::new();
+        let mut log = Vec::new();
+
+        run(&options, &mut output, &mut log).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            "client,available,held,total,locked\n1,2.5000,1.0000,3.5000,false\n"
+        );
+        let log = String::from_utf8(log).unwrap();
+        assert!(log.contains("tx 1 dispute expired\n"));
+    }
+
+    #[test]
+    fn options_parse_dispute_window() {
+        let args = vec!["input.csv", "--dispute-window", "3600"];
+        let options = Options::parse(args.into_iter().map(String::from)).unwrap();
+        assert_eq!(options.dispute_window, Some(3600));
+
+        let args = vec!["input.csv", "--dispute-window", "0"];
+        let options = Options::parse(args.into_iter().map(String::from));
+        assert_eq!(
+            options.err(),
+            Some(String::from("invalid dispute window '0'"))
+        );
+    }
+
+    #[test]
+    fn utc_date_formats_the_date() {
+        let day = |days: u64| UNIX_EPOCH + Duration::from_secs(days * 86_400 + 3600);
+
+        assert_eq!(utc_date(UNIX_EPOCH), "1970-01-01");
+        assert_eq!(utc_date(day(19_723)), "2024-01-01");
+        assert_eq!(utc_date(day(19_782)), "2024-02-29");
+        assert_eq!(utc_date(day(11_016)), "2000-02-29");
+    }
+
+    #[test]
+    fn run_starts_the_summary_with_metadata() {
+        let input = "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,2,2,2.0\ndeposit,1,3\n";
+        let options = Options {
+            input_filename: temp_file("metadata.csv", input),
+            with_metadata: true,
+            ..Options::default()
+        };
+        let mut output = Vec::new();
+
+        run(&options, &mut output, io::sink()).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        let mut lines = output.lines();
+        let metadata = lines.next().unwrap();
+        assert!(metadata.starts_with("# generated "));
+        assert!(metadata.ends_with(&format!("from {}, 2 rows", options.input_filename)));
+        assert_eq!(lines.next(), Some("client,available,held,total,locked"));
+        assert_eq!(lines.count(), 2);
+    }
+
+    #[test]
+    fn run_writes_only_the_header_for_empty_input() {
+        for (name, input) in [
+            ("empty.csv", ""),
+            ("header-only.csv", "type,client,tx,amount\n"),
+        ]
+        .iter()
+        {
+            let options = Options {
+                input_filename: temp_file(name, input),
+                ..Options::default()
+            };
+            let mut output = Vec::new();
+
+            run(&options, &mut output, io::sink()).unwrap();
+
+            let output = String::from_utf8(output).unwrap();
+            assert_eq!(output, "client,available,held,total,locked\n");
+        }
+    }
+
+    #[test]
+    fn run_parses_and_writes_amounts_with_scale() {
+        let input = "type,client,tx,amount\ndeposit,1,1,1.25\ndeposit,1,2,0.125\n";
+        let options = Options {
+            input_filename: temp_file("scale.csv", input),
+            parse: ParseOptions {
+                scale: Some(2),
+                ..ParseOptions::default()
+            },
+            ..Options::default()
+        };
+        let mut output = Vec::new();
+        let mut log = Vec::new();
+
+        run(&options, &mut output, &mut log).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            "client,available,held,total,locked\n1,1.25,0.00,1.25,false\n"
+        );
+        let log = String::from_utf8(log).unwrap();
+        assert_eq!(
+            log,
+            "skipping line 3: invalid 'amount' field '0.125', more than 2 decimal places\n"
+        );
+    }
+
+    #[test]
+    fn run_writes_held_amounts_with_their_own_precision() {
+        let input = "type,client,tx,amount\ndeposit,1,1,1.25\ndeposit,1,2,0.126\ndispute,1,2,\n";
+        let options = Options {
+            input_filename: temp_file("held-precision.csv", input),
+            held_precision: Some(2),
+            ..Options::default()
+        };
+        let mut output = Vec::new();
+
+        run(&options, &mut output, io::sink()).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            "client,available,held,total,locked\n1,1.2500,0.13,1.3760,false\n"
+        );
+    }
+
+    #[test]
+    fn options_parse_precisions_default_to_four_places() {
+        let args = vec!["input.csv", "--held-precision", "2"];
+        let options = Options::parse(args.into_iter().map(String::from)).unwrap();
+        assert_eq!(options.output_scales(), (4, 2));
+
+        let args = vec!["input.csv", "--precision", "3"];
+        let options = Options::parse(args.into_iter().map(String::from)).unwrap();
+        assert_eq!(options.output_scales(), (3, 4));
+
+        let options = Options::parse(vec![String::from("input.csv")].into_iter()).unwrap();
+        assert_eq!(options.output_scales(), (4, 4));
+    }
+
+    #[test]
+    fn process_reader_traces_every_transaction_when_set() {
+        let input = "type,client,tx,amount\n\
+                     deposit,1,5,1.0\n\
+                     withdrawal,1,6,0.25\n\
+                     dispute,1,5,\n\
+                     withdrawal,1,7,5.0\n";
+        let options = Options {
+            trace: true,
+            ..Options::default()
+        };
+        let mut log = Vec::new();
+
+        process_reader(&mut Exchange::new(), input.as_bytes(), &options, &mut log).unwrap();
+
+        let log = String::from_utf8(log).unwrap();
+        assert_eq!(
+            log,
+            "tx 5 deposit client 1 +1.0000 -> available 1.0000\n\
+             tx 6 withdrawal client 1 -0.2500 -> available 0.7500\n\
+             tx 5 dispute client 1 failed: InsufficientFunds\n\
+             tx 7 withdrawal client 1 -5.0000 failed: InsufficientFunds\n"
+        );
+    }
+
+    #[test]
+    fn serve_replies_with_summary_once_sender_is_done() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let sender = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            stream
+                .write_all(b"type,client,tx,amount\ndeposit,1,1,2.0\nwithdrawal,1,2,0.5\n")
+                .unwrap();
+            stream.shutdown(std::net::Shutdown::Write).unwrap();
+
+            let mut summary = String::new();
+            stream.read_to_string(&mut summary).unwrap();
+            summary
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        let mut exchange = Exchange::new();
+        serve(&mut exchange, &stream, &Options::default(), io::sink()).unwrap();
+        drop(stream);
+
+        assert_eq!(
+            sender.join().unwrap(),
+            "client,available,held,total,locked\n1,1.5000,0.0000,1.5000,false\n"
+        );
+    }
+
+    #[test]
+    fn serve_until_shutdown_writes_the_final_summary() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let sender = {
+            let shutdown = Arc::clone(&shutdown);
+            std::thread::spawn(move || {
+                for input in [
+                    "type,client,tx,amount\ndeposit,1,1,2.0\n",
+                    "type,client,tx,amount\ndeposit,2,2,1.0\nwithdrawal,1,3,0.5\n",
+                ]
+                .iter()
+                {
+                    let mut stream = TcpStream::connect(address).unwrap();
+                    stream.write_all(input.as_bytes()).unwrap();
+                    stream.shutdown(std::net::Shutdown::Write).unwrap();
+                    stream.read_to_string(&mut String::new()).unwrap();
+                }
+
+                // as the Ctrl-C handler would
+                request_shutdown(&shutdown, address);
+            })
+        };
+        let mut output = Vec::new();
+
+        serve_until_shutdown(
+            &listener,
+            &shutdown,
+            &Options::default(),
+            &mut output,
+            io::sink(),
+        )
+        .unwrap();
+        sender.join().unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            "client,available,held,total,locked\n\
+             1,1.5000,0.0000,1.5000,false\n\
+             2,1.0000,0.0000,1.0000,false\n"
+        );
+    }
+
+    #[test]
+    fn check_not_negative_reports_clients_with_negative_available_funds() {
+        let mut exchange = ExchangeBuilder::new()
+            .allow_negative_available(true)
+            .build();
+        let mut process = |transaction| exchange.process(transaction).unwrap();
+        process(Transaction::Deposit(
+            2,
+            1,
+            DEFAULT_ASSET.into(),
+            money("1.0"),
+        ));
+        process(Transaction::Withdrawal(
+            2,
+            2,
+            DEFAULT_ASSET.into(),
+            money("0.5"),
+        ));
+        process(Transaction::Dispute(2, 1));
+        process(Transaction::Deposit(
+            1,
+            3,
+            DEFAULT_ASSET.into(),
+            money("1.0"),
+        ));
+
+        assert_eq!(
+            check_not_negative(&exchange),
+            Err(String::from("clients with negative available funds: 2"))
+        );
+
+        exchange.process(Transaction::Resolve(2, 1)).unwrap();
+        assert_eq!(check_not_negative(&exchange), Ok(()));
+    }
+
+    #[test]
+    fn write_clients_pretty_aligns_columns() {
+        let mut exchange = Exchange::new_ordered();
+        exchange
+            .process(Transaction::Deposit(
+                1,
+                1,
+                DEFAULT_ASSET.into(),
+                money("1.5"),
+            ))
+            .unwrap();
+        exchange
+            .process(Transaction::Deposit(
+                12,
+                2,
+                DEFAULT_ASSET.into(),
+                money("1234.0"),
+            ))
+            .unwrap();
+        exchange.process(Transaction::Dispute(12, 2)).unwrap();
+
+        let mut output = Vec::new();
+        write_clients_pretty(&exchange, &mut output, &Options::default(), true).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            "client  available       held      total  locked\n\
+             \x20    1     1.5000     0.0000     1.5000   false\n\
+             \x20   12     0.0000  1234.0000  1234.0000   false\n"
+        );
+    }
+
+    #[test]
+    fn write_clients_pretty_writes_chosen_columns() {
+        let mut exchange = Exchange::new_ordered();
+        exchange
+            .process(Transaction::Deposit(
+                1,
+                1,
+                DEFAULT_ASSET.into(),
+                money("1.5"),
+            ))
+            .unwrap();
+        exchange
+            .process(Transaction::Deposit(
+                12,
+                2,
+                DEFAULT_ASSET.into(),
+                money("1234.0"),
+            ))
+            .unwrap();
+
+        let args = vec!["input.csv", "--pretty", "--columns", "total,client"];
+        let options = Options::parse(args.into_iter().map(String::from)).unwrap();
+        let mut output = Vec::new();
+        write_clients_pretty(&exchange, &mut output, &options, true).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(
+            output,
+            "    total  client\n\
+             \x20  1.5000       1\n\
+             1234.0000      12\n"
+        );
     }
 }
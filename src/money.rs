@@ -0,0 +1,250 @@
+use alloc::format;
+use alloc::string::String;
+use core::fmt::{Debug, Display};
+use core::ops::{Add, AddAssign, Div, Mul, Neg, Sub, SubAssign};
+
+/// The money type used unless another one is chosen. It's `f32`, or an exact
+/// `Decimal` with the `decimal` feature.
+#[cfg(not(feature = "decimal"))]
+pub type Money = f32;
+#[cfg(feature = "decimal")]
+pub type Money = rust_decimal::Decimal;
+
+/// A type that can hold an amount of money. The exchange only ever needs to
+/// add, subtract, and compare amounts, and multiply and divide them for fee
+/// rates, so e.g. a fixed-point or decimal type works as well as a float.
+pub trait Amount:
+    Copy
+    + Debug
+    + Display
+    + PartialOrd
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Mul<Output = Self>
+    + Div<Output = Self>
+    + Neg<Output = Self>
+    + AddAssign
+    + SubAssign
+{
+    /// The amount representing no money.
+    fn zero() -> Self;
+
+    /// The amount representing one unit of money.
+    fn one() -> Self;
+
+    /// The amount as an `f32`, for statistics where exactness doesn't matter.
+    fn to_f32(self) -> f32;
+
+    /// The largest difference between two amounts that are still considered
+    /// equal, to allow for rounding errors. Exact types don't need one.
+    fn epsilon() -> Self {
+        Self::zero()
+    }
+
+    /// The amount from which whole amounts can no longer all be represented
+    /// exactly, so a larger amount may have been silently rounded, or balances
+    /// adding up larger amounts may overflow. Types without a limit on either
+    /// don't have one.
+    fn exact_limit() -> Option<Self> {
+        None
+    }
+
+    /// Whether the amount is a usable number, i.e. not infinite or NaN for
+    /// floats.
+    fn is_valid(self) -> bool {
+        true
+    }
+
+    /// The amount written with exactly `scale` decimal places, rounding half to
+    /// even if needed. See `format::format_amount`, which also drops the sign
+    /// of amounts that round to zero.
+    fn to_fixed(self, scale: u8) -> String {
+        format!("{:.*}", scale as usize, self)
+    }
+
+    /// The amount without its sign.
+    fn abs(self) -> Self {
+        if self < Self::zero() {
+            -self
+        } else {
+            self
+        }
+    }
+}
+
+impl Amount for f32 {
+    fn zero() -> f32 {
+        0.0
+    }
+
+    fn one() -> f32 {
+        1.0
+    }
+
+    fn to_f32(self) -> f32 {
+        self
+    }
+
+    fn epsilon() -> f32 {
+        1e-4
+    }
+
+    fn exact_limit() -> Option<f32> {
+        // 2^24, past which not every integer fits in the 24 bit significand
+        Some(16_777_216.0)
+    }
+
+    fn is_valid(self) -> bool {
+        self.is_finite()
+    }
+}
+
+impl Amount for f64 {
+    fn zero() -> f64 {
+        0.0
+    }
+
+    fn one() -> f64 {
+        1.0
+    }
+
+    fn to_f32(self) -> f32 {
+        self as f32
+    }
+
+    fn epsilon() -> f64 {
+        1e-4
+    }
+
+    fn exact_limit() -> Option<f64> {
+        // 2^53, past which not every integer fits in the 53 bit significand
+        Some(9_007_199_254_740_992.0)
+    }
+
+    fn is_valid(self) -> bool {
+        self.is_finite()
+    }
+}
+
+#[cfg(feature = "decimal")]
+impl Amount for rust_decimal::Decimal {
+    fn zero() -> rust_decimal::Decimal {
+        rust_decimal::Decimal::new(0, 0)
+    }
+
+    fn one() -> rust_decimal::Decimal {
+        rust_decimal::Decimal::new(1, 0)
+    }
+
+    fn to_f32(self) -> f32 {
+        use rust_decimal::prelude::ToPrimitive;
+
+        ToPrimitive::to_f32(&self).unwrap_or(0.0)
+    }
+
+    fn to_fixed(self, scale: u8) -> String {
+        // the precision only truncates a decimal, so round it first
+        format!("{:.*}", scale as usize, self.round_dp(u32::from(scale)))
+    }
+
+    fn exact_limit() -> Option<rust_decimal::Decimal> {
+        // 10^20, leaving 8 of the 28 digits for decimal places, and room for
+        // hundreds of millions of amounts in a balance before it overflows
+        let ten_billion = rust_decimal::Decimal::from(10_000_000_000u64);
+        Some(ten_billion * ten_billion)
+    }
+}
+
+/// Whether the amounts are at most `epsilon` apart.
+pub fn approx_eq<M: Amount>(a: M, b: M, epsilon: M) -> bool {
+    (a - b).abs() <= epsilon
+}
+
+/// Parses the amount as the default `Money`, so tests read the same whether
+/// it's a float or a decimal.
+#[cfg(test)]
+pub(crate) fn money(amount: &str) -> Money {
+    amount.parse().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn abs_drops_the_sign() {
+        assert_eq!(Amount::abs(-2.5f32), 2.5);
+        assert_eq!(Amount::abs(2.5f64), 2.5);
+    }
+
+    #[test]
+    fn floats_are_valid_only_if_finite() {
+        assert!(1.0f32.is_valid());
+        assert!(!f32::NAN.is_valid());
+        assert!(!f64::INFINITY.is_valid());
+    }
+
+    #[test]
+    fn approx_eq_allows_differences_up_to_epsilon() {
+        assert!(approx_eq(0.1f32 + 0.2 - 0.1, 0.2, f32::epsilon()));
+        assert!(!approx_eq(0.2f32, 0.3, f32::epsilon()));
+    }
+}
+
+#[cfg(all(test, feature = "decimal"))]
+mod decimal_tests {
+    use super::*;
+    use crate::client::DEFAULT_ASSET;
+    use crate::exchange::Exchange;
+    use crate::transaction::Transaction;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn decimal_arithmetic_is_exact() {
+        let mut exchange = Exchange::new();
+
+        for line in ["deposit,1,1,0.1", "deposit,1,2,0.2", "withdrawal,1,3,0.3"].iter() {
+            exchange
+                .process(Transaction::try_from(*line).unwrap())
+                .unwrap();
+        }
+        exchange
+            .process(Transaction::try_from("deposit,1,4,1.0001").unwrap())
+            .unwrap();
+
+        let (_, client) = exchange.clients().next().unwrap();
+        let balances = client.balance(DEFAULT_ASSET).unwrap();
+        assert_eq!(balances.funds_available, money("1.0001"));
+        assert_eq!(balances.funds_held, money("0"));
+    }
+
+    #[test]
+    fn decimal_reconciles_exactly() {
+        let mut exchange = Exchange::new();
+
+        exchange
+            .process(Transaction::Deposit(
+                1,
+                1,
+                DEFAULT_ASSET.into(),
+                money("0.1"),
+            ))
+            .unwrap();
+        exchange
+            .process(Transaction::Deposit(
+                1,
+                2,
+                DEFAULT_ASSET.into(),
+                money("0.2"),
+            ))
+            .unwrap();
+        exchange.process(Transaction::Dispute(1, 1)).unwrap();
+
+        let (_, client) = exchange.clients().next().unwrap();
+        assert_eq!(
+            client.balance(DEFAULT_ASSET).unwrap().funds_available,
+            money("0.2")
+        );
+        assert_eq!(exchange.reconcile(), vec![]);
+    }
+}
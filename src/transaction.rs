@@ -1,14 +1,167 @@
-use crate::client::ClientId;
+use crate::client::{Asset, ClientId};
+use crate::money::Money;
 
 /// Transactions are identified by a unique 32-bit number.
 pub type TransactionId = u32;
 
 /// Represents the types of transactions (and their associated data) that can be
 /// used with an Exchange.
-pub enum Transaction {
-    Deposit(ClientId, TransactionId, f32),
-    Withdrawal(ClientId, TransactionId, f32),
+#[derive(Clone, Debug, PartialEq)]
+pub enum Transaction<M = Money> {
+    Deposit(ClientId, TransactionId, Asset, M),
+    Withdrawal(ClientId, TransactionId, Asset, M),
+
+    /// Moves funds from the first client to the second. It fails without
+    /// changing either account if the sender's is locked or doesn't have
+    /// enough funds.
+    Transfer(ClientId, ClientId, TransactionId, Asset, M),
+
     Dispute(ClientId, TransactionId),
     Resolve(ClientId, TransactionId),
     Chargeback(ClientId, TransactionId),
+
+    /// Marks the start of a new session for the client. Its transaction ID
+    /// only identifies the row and isn't recorded. See
+    /// `ExchangeBuilder::session_disputes`.
+    SessionBoundary(ClientId, TransactionId),
+
+    /// Locks the client's account without a chargeback, e.g. while an
+    /// operator looks into it. Its transaction ID only identifies the row and
+    /// isn't recorded. See `ExchangeBuilder::enforce_locks`.
+    Freeze(ClientId, TransactionId),
+
+    /// Unlocks the client's account, whatever locked it. Its transaction ID
+    /// only identifies the row and isn't recorded.
+    Unfreeze(ClientId, TransactionId),
+}
+
+impl<M: Copy> Transaction<M> {
+    /// The transaction's type as written in the input, like `deposit`.
+    pub fn kind(&self) -> &'static str {
+        use Transaction::*;
+
+        match self {
+            Deposit(..) => "deposit",
+            Withdrawal(..) => "withdrawal",
+            Transfer(..) => "transfer",
+            Dispute(..) => "dispute",
+            Resolve(..) => "resolve",
+            Chargeback(..) => "chargeback",
+            SessionBoundary(..) => "session",
+            Freeze(..) => "freeze",
+            Unfreeze(..) => "unfreeze",
+        }
+    }
+
+    /// The client the transaction is for, the sender for transfers.
+    pub fn client(&self) -> ClientId {
+        use Transaction::*;
+
+        match self {
+            Deposit(client, ..) | Withdrawal(client, ..) | Transfer(client, ..) => *client,
+            Dispute(client, _) | Resolve(client, _) | Chargeback(client, _) => *client,
+            SessionBoundary(client, _) | Freeze(client, _) | Unfreeze(client, _) => *client,
+        }
+    }
+
+    /// The ID of the transaction, or of the transaction it refers to for
+    /// disputes, resolves, and chargebacks.
+    pub fn tx_id(&self) -> TransactionId {
+        use Transaction::*;
+
+        match self {
+            Deposit(_, tx, ..) | Withdrawal(_, tx, ..) | Transfer(_, _, tx, ..) => *tx,
+            Dispute(_, tx) | Resolve(_, tx) | Chargeback(_, tx) => *tx,
+            SessionBoundary(_, tx) | Freeze(_, tx) | Unfreeze(_, tx) => *tx,
+        }
+    }
+
+    /// The amount moved by a deposit, withdrawal, or transfer. Other
+    /// transactions don't have one.
+    pub fn amount(&self) -> Option<M> {
+        use Transaction::*;
+
+        match self {
+            Deposit(_, _, _, amount) | Withdrawal(_, _, _, amount) => Some(*amount),
+            Transfer(_, _, _, _, amount) => Some(*amount),
+            Dispute(..) | Resolve(..) | Chargeback(..) => None,
+            SessionBoundary(..) | Freeze(..) | Unfreeze(..) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::money::money;
+
+    #[test]
+    fn deposit_accessors() {
+        let transaction: Transaction = Transaction::Deposit(1, 2, "BTC".into(), money("3.0"));
+
+        assert_eq!(transaction.client(), 1);
+        assert_eq!(transaction.tx_id(), 2);
+        assert_eq!(transaction.amount(), Some(money("3.0")));
+    }
+
+    #[test]
+    fn withdrawal_accessors() {
+        let transaction: Transaction = Transaction::Withdrawal(1, 2, "BTC".into(), money("3.0"));
+
+        assert_eq!(transaction.client(), 1);
+        assert_eq!(transaction.tx_id(), 2);
+        assert_eq!(transaction.amount(), Some(money("3.0")));
+    }
+
+    #[test]
+    fn transfer_accessors() {
+        let transaction: Transaction = Transaction::Transfer(1, 4, 2, "BTC".into(), money("3.0"));
+
+        assert_eq!(transaction.client(), 1);
+        assert_eq!(transaction.tx_id(), 2);
+        assert_eq!(transaction.amount(), Some(money("3.0")));
+    }
+
+    #[test]
+    fn kind_is_input_type() {
+        let transaction: Transaction = Transaction::Deposit(1, 2, "BTC".into(), money("3.0"));
+
+        assert_eq!(transaction.kind(), "deposit");
+        assert_eq!(
+            Transaction::<Money>::Transfer(1, 4, 2, "BTC".into(), money("3.0")).kind(),
+            "transfer"
+        );
+        assert_eq!(
+            Transaction::<Money>::SessionBoundary(1, 2).kind(),
+            "session"
+        );
+        assert_eq!(Transaction::<Money>::Freeze(1, 2).kind(), "freeze");
+    }
+
+    #[test]
+    fn dispute_accessors() {
+        let transaction: Transaction = Transaction::Dispute(1, 2);
+
+        assert_eq!(transaction.client(), 1);
+        assert_eq!(transaction.tx_id(), 2);
+        assert_eq!(transaction.amount(), None);
+    }
+
+    #[test]
+    fn resolve_accessors() {
+        let transaction: Transaction = Transaction::Resolve(1, 2);
+
+        assert_eq!(transaction.client(), 1);
+        assert_eq!(transaction.tx_id(), 2);
+        assert_eq!(transaction.amount(), None);
+    }
+
+    #[test]
+    fn chargeback_accessors() {
+        let transaction: Transaction = Transaction::Chargeback(1, 2);
+
+        assert_eq!(transaction.client(), 1);
+        assert_eq!(transaction.tx_id(), 2);
+        assert_eq!(transaction.amount(), None);
+    }
 }